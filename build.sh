@@ -8,14 +8,33 @@ die() {
 
 cargo --version >/dev/null || die "Must have cargo installed."
 
+# Pass --test-endpoints to export the mocked endpoints used by the tests.
+features=""
+if [ "$1" = "--test-endpoints" ]; then
+	features="--features icp-perun/test-endpoints"
+fi
+
 export RUSTFLAGS="--remap-path-prefix=\"${PWD}\"=./ --remap-path-prefix=\"${HOME}\"=_/"
-cargo build --release --target wasm32-unknown-unknown
+cargo build --release --target wasm32-unknown-unknown --workspace $features
+
+# Production builds must not export the mocked endpoints, which credit and
+# withdraw funds without ledger transfers.
+if [ -z "$features" ]; then
+	wasm=target/wasm32-unknown-unknown/release/icp_perun.wasm
+	for method in deposit_mocked withdraw_mocked; do
+		if grep -qa "canister_update $method" "$wasm"; then
+			die "$wasm exports the test endpoint $method."
+		fi
+	done
+fi
 
 echo "Installing ic-cdk-optimizer…"
 if cargo install ic-cdk-optimizer --root target -q; then
-	target/bin/ic-cdk-optimizer \
-		target/wasm32-unknown-unknown/release/icp_perun.wasm \
-		-o target/wasm32-unknown-unknown/release/icp_perun-opt.wasm
+	for canister in icp_perun icp_perun_events; do
+		target/bin/ic-cdk-optimizer \
+			target/wasm32-unknown-unknown/release/$canister.wasm \
+			-o target/wasm32-unknown-unknown/release/$canister-opt.wasm
+	done
 else
 	die "Could not install ic-cdk-optimizer (see above)."
 fi
@@ -0,0 +1,123 @@
+//  Copyright 2022 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Standalone event canister. Stores the events of a Perun canister that is
+//! configured to forward its events here, so that event storage can scale
+//! independently of the Perun canister.
+
+// The Perun canister is a cdylib and cannot be depended upon, so the modules
+// defining the events are shared on source level. They contain no endpoints.
+#[path = "../../src/error.rs"]
+pub mod error;
+#[path = "../../src/events.rs"]
+pub mod events;
+#[path = "../../src/icp.rs"]
+pub mod icp;
+#[path = "../../src/types.rs"]
+pub mod types;
+
+use events::{CanisterState, Event, EventMetrics, EventPage};
+use ic_cdk::export::Principal;
+use lazy_static::lazy_static;
+use std::sync::RwLock;
+use types::{ChannelId, Duration, L2Account, Timestamp};
+
+lazy_static! {
+	static ref STATE: RwLock<CanisterState> =
+		RwLock::new(CanisterState::new(Principal::anonymous(), None));
+}
+
+#[ic_cdk_macros::init]
+/// Configures the Perun canister whose events are stored and how long to keep
+/// the events of concluded channels. Events are kept forever if no retention
+/// period is given.
+fn init(perun_canister: Principal, retention: Option<Duration>) {
+	*STATE.write().unwrap() = CanisterState::new(perun_canister, retention);
+}
+
+#[ic_cdk_macros::heartbeat]
+/// Garbage collects the stored events periodically.
+fn heartbeat() {
+	STATE.write().unwrap().collect_garbage(ic_cdk::api::time());
+}
+
+#[ic_cdk_macros::pre_upgrade]
+/// Writes the stored events into stable memory before upgrading.
+fn pre_upgrade() {
+	let state = STATE.read().unwrap().clone();
+	ic_cdk::storage::stable_save((state,)).expect("writing stable memory");
+}
+
+#[ic_cdk_macros::post_upgrade]
+/// Restores the stored events from stable memory after upgrading.
+fn post_upgrade() {
+	let (state,): (CanisterState,) =
+		ic_cdk::storage::stable_restore().expect("restoring stable memory");
+	*STATE.write().unwrap() = state;
+}
+
+#[ic_cdk_macros::update]
+/// Stores an event of a channel along with the channel's participants, given
+/// the Perun canister's sequence number of the event. Calls by any other
+/// principal than the Perun canister are rejected, so that the Perun canister
+/// does not consider their events as forwarded. Events that were already
+/// stored are ignored, and events that arrive out of order are rejected.
+fn register_event(seq: u64, ch: ChannelId, time: Timestamp, e: Event, members: Vec<L2Account>) {
+	let result =
+		STATE
+			.write()
+			.unwrap()
+			.register_event(&ic_cdk::caller(), seq, time, ch, e, &members);
+	if let Err(e) = result {
+		ic_cdk::trap(&format!("registering event: {}", e));
+	}
+}
+
+#[ic_cdk_macros::query]
+/// Returns the events of a channel that were registered at or after the given
+/// time.
+fn query_events(ch: ChannelId, start: Timestamp) -> Vec<Event> {
+	STATE.read().unwrap().events_after(&ch, start)
+}
+
+#[ic_cdk_macros::query]
+/// Returns up to `limit` events of a channel, starting at the sequence number
+/// `cursor`, along with the cursor of the next page.
+fn query_events_page(ch: ChannelId, cursor: u64, limit: u64) -> EventPage {
+	STATE.read().unwrap().events_page(&ch, cursor, limit)
+}
+
+#[ic_cdk_macros::query]
+/// Returns up to `limit` events of all channels, starting at the sequence
+/// number `cursor`, along with the cursor of the next page.
+fn query_all_events(cursor: u64, limit: u64) -> EventPage {
+	STATE.read().unwrap().all_events(cursor, limit)
+}
+
+#[ic_cdk_macros::query]
+/// Returns up to `limit` events of the channels in which a participant takes
+/// part, starting at the sequence number `cursor`, along with the cursor of the
+/// next page.
+fn query_participant_events(who: L2Account, cursor: u64, limit: u64) -> EventPage {
+	STATE
+		.read()
+		.unwrap()
+		.participant_events(&who, cursor, limit)
+}
+
+#[ic_cdk_macros::query]
+/// Returns how many events are stored and the oldest one's time.
+fn query_event_metrics() -> EventMetrics {
+	STATE.read().unwrap().metrics()
+}
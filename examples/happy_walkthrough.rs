@@ -18,10 +18,8 @@ use ic_agent::{
 	agent::http_transport::ReqwestHttpReplicaV2Transport, ic_types::Principal,
 	identity::BasicIdentity, Agent, Identity,
 };
-use ic_ledger_types::{
-	AccountIdentifier, Memo, Tokens, TransferArgs, TransferResult, DEFAULT_SUBACCOUNT,
-};
-use icp_perun::{test, types::*};
+use ic_ledger_types::{AccountIdentifier, Memo, Tokens, TransferArgs, TransferResult};
+use icp_perun::{icp, test, types::*};
 use log::{error, info};
 use std::{env, error, result::Result, time::Duration};
 
@@ -56,14 +54,15 @@ async fn walkthrough(cid: Principal, lid: Principal, url: String) -> Result<(),
 	demo.query_holdings(alice).await?;
 	demo.query_holdings(bob).await?;
 	// Deposit for Alice and Bob.
-	demo.deposit(&demo.setup.state.allocation[alice], alice)
+	demo.deposit(&demo.setup.state.allocation[0][alice], alice)
+		.await?;
+	demo.deposit(&demo.setup.state.allocation[0][bob], bob)
 		.await?;
-	demo.deposit(&demo.setup.state.allocation[bob], bob).await?;
 	// Query on-chain balances.
 	demo.query_holdings(alice).await?;
 	demo.query_holdings(bob).await?;
 	// Update off-chain balances.
-	demo.setup.state.allocation.swap(alice, bob);
+	demo.setup.state.allocation[0].swap(alice, bob);
 	// Conclude the channel.
 	demo.conclude().await?;
 	let state = demo.query_state().await?.unwrap();
@@ -95,8 +94,14 @@ impl Demo {
 			.timeout(Duration::from_secs(60 * 5))
 			.build();
 
+		// Hold ICP of the locally deployed ledger in the channel.
+		let mut setup = test::Setup::new(finalized, false);
+		setup.params.assets[0].ledger = ledger;
+		setup.state.channel = setup.params.id();
+		setup.canister_id = L1Account::from_text(canister.to_string()).unwrap();
+
 		Ok(Self {
-			setup: test::Setup::new(finalized, false),
+			setup,
 			agent,
 			canister,
 			ledger,
@@ -111,22 +116,21 @@ impl Demo {
 			fid.channel, part, amount
 		);
 
-		let mut amount_str = amount.to_string();
-		amount_str.retain(|c| c != '_');
-		let amount_u64 = amount_str.parse::<u64>().unwrap();
+		// The canister pays the fee for sweeping the deposit address.
+		let amount_u64 = icp::to_u64(amount).unwrap() + icp::DEFAULT_FEE_E8S;
 
 		let bytes = self
 			.agent
 			.update(&self.ledger, "transfer")
 			.with_arg(
 				Encode!(&TransferArgs {
-					memo: Memo(fid.memo()),
+					memo: Memo(0),
 					amount: Tokens::from_e8s(amount_u64),
 					fee: Tokens::from_e8s(0),
 					from_subaccount: None,
 					to: AccountIdentifier::new(
 						&L1Account::from_text(self.canister.to_string()).unwrap(),
-						&DEFAULT_SUBACCOUNT
+						&fid.subaccount()
 					),
 					created_at_time: None,
 				})
@@ -136,27 +140,7 @@ impl Demo {
 			.await?;
 		let transfer_result = Some(Decode!(&bytes, TransferResult).unwrap());
 		let block = transfer_result.unwrap().expect("transfer should not fail");
-		info!("notifying canister of receipt {}", block);
-		info!(
-			"received: {}",
-			Decode!(
-				&self
-					.agent
-					.update(&self.canister, "transaction_notification")
-					.with_arg(Encode!(&block).unwrap())
-					.call_and_wait(self.delay.clone())
-					.await?,
-				icp_perun::error::Result<Amount>
-			)
-			.unwrap()
-			.map_or_else(|e| e.to_string(), |n| n.to_string())
-		);
-		info!("notifying canister of receipt (again ;) )");
-		self.agent
-			.update(&self.canister, "transaction_notification")
-			.with_arg(Encode!(&block).unwrap())
-			.call_and_wait(self.delay.clone())
-			.await?;
+		info!("transferred to deposit address in block {}", block);
 		info!("triggering deposit");
 		self.agent
 			.update(&self.canister, "deposit")
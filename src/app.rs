@@ -0,0 +1,79 @@
+//  Copyright 2022 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! App channels, whose state transitions are governed by the rules of an app.
+//! During a dispute, a single participant can advance an app channel's state
+//! if the app allows the transition, see [crate::CanisterState::progress].
+
+use crate::error::{Error, Result};
+use crate::require;
+use crate::tictactoe;
+use crate::types::*;
+
+/// The rules of an app channel.
+pub trait App: Sync {
+	/// Checks that a state is a valid initial state of the app. Not called for
+	/// finalized states, which all participants agreed on.
+	fn valid_init(&self, params: &Params, state: &State) -> Result<()>;
+
+	/// Checks that the participant with index `actor` may advance the channel
+	/// from state `from` to state `to`. The generic requirements, i.e., matching
+	/// channel and version and preserved funds, are already checked.
+	fn valid_transition(
+		&self,
+		params: &Params,
+		from: &State,
+		to: &State,
+		actor: usize,
+	) -> Result<()>;
+}
+
+/// Returns the built-in app with the given identifier.
+pub fn app(id: &str) -> Option<&'static dyn App> {
+	match id {
+		tictactoe::APP_ID => Some(&tictactoe::TicTacToe),
+		_ => None,
+	}
+}
+
+/// Returns the app of a channel, or nothing for payment channels. Fails for
+/// unknown apps.
+pub fn channel_app(params: &Params) -> Result<Option<&'static dyn App>> {
+	match &params.app {
+		None => Ok(None),
+		Some(id) => app(id).map(Some).ok_or(Error::UnsupportedApp),
+	}
+}
+
+/// Checks the requirements of every state transition: the channel and assets
+/// stay the same, the version increases by one, the old state is not final,
+/// the funds locked in sub-channels stay the same, and the funds of each asset
+/// are only redistributed.
+pub fn valid_transition_generic(from: &State, to: &State) -> Result<()> {
+	require!(to.channel == from.channel, InvalidInput);
+	require!(!from.finalized, AlreadyConcluded);
+	require!(to.version == from.version + 1, OutdatedState);
+	require!(
+		to.allocation.len() == from.allocation.len()
+			&& to
+				.allocation
+				.iter()
+				.zip(from.allocation.iter())
+				.all(|(t, f)| t.len() == f.len()),
+		InvalidTransition
+	);
+	require!(to.locked == from.locked, InvalidTransition);
+	require!(to.total() == from.total(), InvalidTransition);
+	Ok(())
+}
@@ -0,0 +1,243 @@
+//  Copyright 2022 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Certification of query responses. The canister keeps a hash tree over the
+//! registered channel states, the holdings and the latest event of each
+//! channel, and certifies its root hash via `set_certified_data`. Certified
+//! queries return the queried value along with the system's certificate and a
+//! witness, i.e., the hash tree pruned down to the queried key. This module
+//! does not validate certificates: clients have to check the certificate's
+//! signature against the Internet Computer's root key and extract the
+//! canister's certified data themselves, e.g., using the agent library, and
+//! can then check the witness against it via [verify].
+
+use crate::types::*;
+use candid::Encode;
+use ic_certified_map::{AsHashTree, HashTree, RbTree};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+/// A hash as used by the certified data tree.
+pub type TreeHash = [u8; 32];
+
+/// Key prefix of the registered channel states.
+const STATE_PREFIX: &[u8] = b"state/";
+/// Key prefix of the holdings.
+const HOLDINGS_PREFIX: &[u8] = b"holdings/";
+/// Key prefix of the channels' latest events.
+const EVENTS_PREFIX: &[u8] = b"events/";
+
+#[derive(Clone, PartialEq, Eq, Debug, CandidType, Deserialize)]
+/// A pruned copy of the certified data tree, see the Internet Computer's
+/// interface specification on certification.
+pub enum Witness {
+	Empty,
+	Fork(Box<Witness>, Box<Witness>),
+	Labeled(ByteBuf, Box<Witness>),
+	Leaf(ByteBuf),
+	Pruned(ByteBuf),
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+/// A query response along with the data needed to verify it.
+pub struct Certified<T> {
+	pub value: T,
+	/// The system's certificate of the canister's certified data. Empty if the
+	/// response was produced by an update call.
+	pub certificate: ByteBuf,
+	/// The witness of the queried value in the certified data tree.
+	pub witness: Witness,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+/// The result of looking up a key in a witness.
+pub enum Lookup<'a> {
+	/// The key is present, with the given value hash.
+	Found(&'a [u8]),
+	/// The witness proves that the key is not present.
+	Absent,
+	/// The key was pruned from the witness.
+	Unknown,
+}
+
+/// The hash tree over all certified values. Each value is stored as the
+/// SHA-256 hash of its candid encoding.
+pub struct CertifiedTree {
+	tree: RbTree<Vec<u8>, TreeHash>,
+}
+
+impl Default for CertifiedTree {
+	fn default() -> Self {
+		Self {
+			tree: RbTree::new(),
+		}
+	}
+}
+
+impl CertifiedTree {
+	/// Inserts a value under a key, or removes the key if there is no value.
+	pub fn set<T: CandidType>(&mut self, key: Vec<u8>, value: Option<&T>) {
+		match value {
+			Some(v) => self.tree.insert(key, value_hash(v)),
+			None => self.tree.delete(&key),
+		}
+	}
+
+	/// The hash to certify via `set_certified_data`.
+	pub fn root_hash(&self) -> TreeHash {
+		self.tree.root_hash()
+	}
+
+	/// Returns the witness for a key, proving either its value or its absence.
+	pub fn witness(&self, key: &[u8]) -> Witness {
+		Witness::from(&self.tree.witness(key))
+	}
+}
+
+impl From<&HashTree<'_>> for Witness {
+	fn from(tree: &HashTree) -> Self {
+		match tree {
+			HashTree::Empty => Witness::Empty,
+			HashTree::Fork(f) => Witness::Fork(Box::new((&f.0).into()), Box::new((&f.1).into())),
+			HashTree::Labeled(l, t) => {
+				Witness::Labeled(ByteBuf::from(l.to_vec()), Box::new((&**t).into()))
+			}
+			HashTree::Leaf(v) => Witness::Leaf(ByteBuf::from(v.to_vec())),
+			HashTree::Pruned(h) => Witness::Pruned(ByteBuf::from(h.to_vec())),
+		}
+	}
+}
+
+impl Witness {
+	/// Computes the root hash of the tree that the witness was pruned from.
+	pub fn reconstruct(&self) -> TreeHash {
+		match self {
+			Witness::Empty => domain_hash("ic-hashtree-empty", &[]),
+			Witness::Fork(l, r) => {
+				domain_hash("ic-hashtree-fork", &[&l.reconstruct(), &r.reconstruct()])
+			}
+			Witness::Labeled(l, t) => {
+				domain_hash("ic-hashtree-labeled", &[l.as_slice(), &t.reconstruct()])
+			}
+			Witness::Leaf(v) => domain_hash("ic-hashtree-leaf", &[v.as_slice()]),
+			Witness::Pruned(h) => h.as_slice().try_into().unwrap_or_default(),
+		}
+	}
+
+	/// Looks up a top-level key in the witness.
+	pub fn lookup(&self, key: &[u8]) -> Lookup {
+		let mut nodes = vec![];
+		self.flatten(&mut nodes);
+		if let Some(Witness::Labeled(_, t)) = nodes
+			.iter()
+			.copied()
+			.find(|n| matches!(n, Witness::Labeled(l, _) if l.as_slice() == key))
+		{
+			return match &**t {
+				Witness::Leaf(v) => Lookup::Found(v.as_slice()),
+				_ => Lookup::Unknown,
+			};
+		}
+
+		// The key is absent if no pruned subtree could contain it, i.e., if
+		// none lies between the labels adjacent to it.
+		let label = |n: &Witness| match n {
+			Witness::Labeled(l, _) => Some(l.to_vec()),
+			_ => None,
+		};
+		let lower = nodes
+			.iter()
+			.rposition(|n| label(n).map_or(false, |l| l.as_slice() < key))
+			.map_or(0, |i| i + 1);
+		let upper = nodes
+			.iter()
+			.position(|n| label(n).map_or(false, |l| l.as_slice() > key))
+			.unwrap_or(nodes.len());
+		if nodes[lower..upper.max(lower)]
+			.iter()
+			.any(|n| matches!(n, Witness::Pruned(_)))
+		{
+			Lookup::Unknown
+		} else {
+			Lookup::Absent
+		}
+	}
+
+	/// Collects the labeled and pruned nodes below the top-level forks.
+	fn flatten<'a>(&'a self, nodes: &mut Vec<&'a Witness>) {
+		match self {
+			Witness::Fork(l, r) => {
+				l.flatten(nodes);
+				r.flatten(nodes);
+			}
+			Witness::Empty => {}
+			n => nodes.push(n),
+		}
+	}
+}
+
+/// Checks a certified query response's witness against the canister's
+/// certified data. Does not validate the certificate itself: the client has
+/// to check its signature against the Internet Computer's root key and look up
+/// `certified_data` in it beforehand, e.g., using the agent library, as only
+/// that protects against a malicious replica. Returns whether the witness
+/// matches the certified data and proves the given value, or the key's absence
+/// if there is no value.
+pub fn verify<T: CandidType>(
+	certified_data: &[u8],
+	witness: &Witness,
+	key: &[u8],
+	value: Option<&T>,
+) -> bool {
+	if witness.reconstruct().as_slice() != certified_data {
+		return false;
+	}
+	match (witness.lookup(key), value) {
+		(Lookup::Found(hash), Some(v)) => hash == value_hash(v).as_slice(),
+		(Lookup::Absent, None) => true,
+		_ => false,
+	}
+}
+
+/// The key of a channel's registered state.
+pub fn state_key(channel: &ChannelId) -> Vec<u8> {
+	[STATE_PREFIX, channel.0.as_slice()].concat()
+}
+
+/// The key of a funding's holdings.
+pub fn holdings_key(funding: &Funding) -> Vec<u8> {
+	let funding = Encode!(funding).expect("encoding funding");
+	[HOLDINGS_PREFIX, &funding].concat()
+}
+
+/// The key of a channel's latest [crate::events::EventRecord].
+pub fn events_key(channel: &ChannelId) -> Vec<u8> {
+	[EVENTS_PREFIX, channel.0.as_slice()].concat()
+}
+
+/// The hash under which a value is stored in the certified data tree.
+pub fn value_hash<T: CandidType>(value: &T) -> TreeHash {
+	Sha256::digest(&Encode!(value).expect("encoding certified value")).into()
+}
+
+/// Hashes the concatenation of a domain separator and the given parts.
+fn domain_hash(domain: &str, parts: &[&[u8]]) -> TreeHash {
+	let mut h = Sha256::new()
+		.chain([domain.len() as u8])
+		.chain(domain.as_bytes());
+	for part in parts {
+		h = h.chain(*part);
+	}
+	h.finalize().into()
+}
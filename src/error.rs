@@ -48,6 +48,35 @@ pub enum Error {
 	OutdatedState,
 	/// Error while interaction with the ledger.
 	LedgerError,
+	/// An asset that is not supported by the canister's configuration.
+	UnsupportedAsset,
+	/// A channel whose app is not built into the canister.
+	UnsupportedApp,
+	/// A state transition that the channel's app does not allow.
+	InvalidTransition,
+	/// When advancing the state of a channel that is not disputed.
+	NotDisputed,
+	/// When advancing a disputed app channel's state before the dispute's
+	/// challenge duration elapsed.
+	ChallengePending,
+	/// A channel whose funds were all withdrawn after its conclusion.
+	AlreadyWithdrawn,
+	/// When concluding a channel that locks funds in a sub-channel without
+	/// the sub-channel's final state.
+	SubChannelNotConcluded,
+	/// When withdrawing funds that do not exceed the ledger's transfer fee.
+	WithdrawalBelowFee,
+	/// The outcome of a withdrawal's ledger transfer is unknown. Repeating the
+	/// withdrawal resolves it without paying out twice.
+	WithdrawalPending,
+	/// A withdrawal request was submitted after its validity period.
+	WithdrawalExpired,
+	/// A withdrawal request's nonce does not exceed the nonce of the funding's
+	/// previous withdrawal, e.g., because the request is replayed, or no
+	/// greater nonce is left.
+	WithdrawalNonceUsed,
+	/// When a call does not attach enough cycles to pay for its fee.
+	InsufficientCycles,
 	/// Error receiving ICP tokens.
 	ReceiverError(crate::icp::ICPReceiverError),
 }
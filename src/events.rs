@@ -12,121 +12,481 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
-use async_trait::async_trait;
 use ic_cdk::export::Principal;
 use lazy_static::lazy_static;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::sync::RwLock;
 
+use crate::error::{Error, Result};
+use crate::require;
 use crate::types::*;
 
 lazy_static! {
 	pub static ref STATE: RwLock<LocalEventRegisterer> = RwLock::new(LocalEventRegisterer::new());
-}
-
-/*
-#[ic_cdk_macros::update]
-async fn register_event(ch: ChannelId, time: Timestamp, e: Event) {
-	STATE.write().unwrap().register_event(time, ch, e).await;
-}
-*/
-
-#[ic_cdk_macros::query]
-fn query_events(ch: ChannelId, start: Timestamp) -> Vec<Event> {
-	STATE.read().unwrap().events_after(&ch, start)
+	/// The events waiting to be forwarded to the event canister.
+	pub static ref OUTBOX: RwLock<Outbox> = RwLock::new(Default::default());
 }
 
 #[derive(Clone, CandidType, Deserialize)]
 pub enum Event {
-	/// A participant supplied funds into the channel.
-	Funded { who: L2Account, total: Amount },
+	/// A participant supplied funds of an asset into the channel.
+	Funded {
+		who: L2Account,
+		asset: Asset,
+		total: Amount,
+	},
 	/// A dispute was started or refuted, along with the latest channel.
 	Disputed(RegisteredState),
+	/// A disputed app channel's state was advanced by a single participant
+	/// according to the app's rules, along with the new state.
+	Progressed(RegisteredState),
 	/// Channel is now concluded and all funds can be withdrawn, no further updates are possible.
 	Concluded,
+	/// A participant's funds of an asset were paid out via the asset's ledger.
+	Withdrawn {
+		who: L2Account,
+		asset: Asset,
+		receiver: L1Account,
+		amount: Amount,
+		fee: Amount,
+		block: crate::icp::BlockHeight,
+	},
+}
+
+/// The maximum number of events returned per page, which keeps pages below the
+/// query response size limit.
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+/// The maximum number of events scanned per filtered page, which keeps
+/// filtered queries below the instruction limit.
+pub const MAX_SCAN_SIZE: u64 = 10_000;
+
+/// How often the event log is garbage collected.
+pub const GC_INTERVAL: Duration = 60 * 60 * 1_000_000_000;
+
+/// A registered event along with its position in the event log.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct EventRecord {
+	/// The event's sequence number, which is unique across all channels and
+	/// increases with every registered event.
+	pub seq: u64,
+	/// The time at which the event was registered.
+	pub time: Timestamp,
+	/// The channel the event belongs to.
+	pub channel: ChannelId,
+	pub event: Event,
+}
+
+/// A page of events, along with the cursor to query the next page with.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct EventPage {
+	pub events: Vec<EventRecord>,
+	/// The sequence number following the page's last event or, for filtered
+	/// pages, following the last scanned event. Equals the queried cursor if
+	/// there are no further events.
+	pub next_cursor: u64,
 }
 
-#[async_trait]
-pub trait EventRegisterer {
-	async fn register_event(&mut self, time: Timestamp, ch: ChannelId, e: Event);
+/// Statistics about the stored events.
+#[derive(Clone, CandidType, Deserialize, PartialEq, Eq, Debug)]
+pub struct EventMetrics {
+	/// The number of stored events.
+	pub retained: u64,
+	/// The registration time of the oldest stored event, if any.
+	pub oldest: Option<Timestamp>,
 }
 
+/// Forwards events to a standalone event canister. Events are queued in the
+/// [OUTBOX] until the event canister accepted them.
 pub struct RPCEventRegisterer {
 	event_canister: Principal,
 }
 
-#[async_trait]
-impl EventRegisterer for RPCEventRegisterer {
-	async fn register_event(&mut self, time: Timestamp, ch: ChannelId, e: Event) {
-		let () = ic_cdk::call(self.event_canister, &"register_event", (ch, time, e))
-			.await
-			.unwrap();
-	}
+/// The events waiting to be forwarded to the event canister, in registration
+/// order. An event is only removed once the event canister accepted it, so
+/// that failed calls neither lose nor reorder events. An event whose call's
+/// response was lost is forwarded again, and recognized as a duplicate by its
+/// sequence number.
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct Outbox {
+	/// The events along with their sequence number and their channel's
+	/// participants.
+	queue: VecDeque<(u64, Timestamp, ChannelId, Event, Vec<L2Account>)>,
+	/// The sequence number of the next queued event.
+	next_seq: u64,
+	/// When the call that is currently forwarding the queue's first event
+	/// started, if any. Expires after [SEND_TIMEOUT] in case the call's
+	/// callback trapped before it was recorded.
+	sending: Option<Timestamp>,
 }
 
-/// The event canister's state. Contains
+/// How long a call forwarding an event blocks further calls, unless its
+/// outcome is recorded earlier.
+pub const SEND_TIMEOUT: Duration = 5 * 60 * 1_000_000_000;
+
+/// The event canister's state. Contains the events registered by the Perun
+/// canister, which is the only principal allowed to register events.
+#[derive(Clone, CandidType, Deserialize)]
 pub struct CanisterState {
 	perun_canister: Principal,
+	/// How long to keep the events of concluded channels, forever if not set.
+	retention: Option<Duration>,
+	/// The Perun canister's sequence number of the last stored event.
+	last_seq: Option<u64>,
 	imple: LocalEventRegisterer,
 }
 
+#[derive(Clone, Default, CandidType, Deserialize)]
 pub struct LocalEventRegisterer {
-	/// All currently stored events.
-	events: BTreeMap<ChannelId, BTreeMap<Timestamp, Vec<Event>>>,
+	/// All currently stored events per channel, by sequence number.
+	events: BTreeMap<ChannelId, BTreeMap<u64, EventRecord>>,
+	/// The channel of each stored event, by sequence number.
+	index: BTreeMap<u64, ChannelId>,
+	/// The participants of each channel, as far as they are named by its
+	/// events or its parameters. Also used to notify subscribers of
+	/// participants if events are forwarded to the event canister.
+	members: BTreeMap<ChannelId, Vec<L2Account>>,
+	/// The sequence number of the next registered event.
+	next_seq: u64,
+	/// The channels for which a conclusion was registered. Only their events
+	/// are garbage collected.
+	concluded: BTreeSet<ChannelId>,
+	/// The time after which the next garbage collection is due.
+	next_gc: Timestamp,
 }
 
-#[async_trait]
-impl EventRegisterer for LocalEventRegisterer {
-	async fn register_event(&mut self, time: Timestamp, ch: ChannelId, e: Event) {
-		let events = self.events.entry(ch).or_insert(Default::default());
-		events.entry(time).or_insert(Default::default()).push(e);
+impl Event {
+	/// Returns the participant named by the event, if any.
+	pub fn participant(&self) -> Option<&L2Account> {
+		match self {
+			Event::Funded { who, .. } | Event::Withdrawn { who, .. } => Some(who),
+			Event::Disputed(_) | Event::Progressed(_) | Event::Concluded => None,
+		}
 	}
 }
 
-#[async_trait]
-impl EventRegisterer for CanisterState {
-	async fn register_event(&mut self, time: Timestamp, ch: ChannelId, e: Event) {
-		if ic_cdk::api::caller() != self.perun_canister {
-			return;
+impl LocalEventRegisterer {
+	/// Stores an event and returns its sequence number.
+	pub fn push(&mut self, time: Timestamp, ch: ChannelId, event: Event) -> u64 {
+		let seq = self.next_seq;
+		self.next_seq += 1;
+		if let Event::Concluded = event {
+			self.concluded.insert(ch.clone());
+		}
+		if let Some(who) = event.participant() {
+			self.add_members(&ch, std::slice::from_ref(who));
 		}
-		self.imple.register_event(time, ch, e).await;
+		let record = EventRecord {
+			seq,
+			time,
+			channel: ch.clone(),
+			event,
+		};
+		self.index.insert(seq, ch.clone());
+		self.events.entry(ch).or_default().insert(seq, record);
+		seq
 	}
-}
 
-impl LocalEventRegisterer {
 	pub fn events_after(&self, ch: &ChannelId, time: Timestamp) -> Vec<Event> {
 		self.events.get(ch).map_or(vec![], |events| {
-			let mut ret = vec![];
-			for (_, es) in events.range(time..) {
-				ret.extend(es.iter().cloned());
-			}
-			ret
+			events
+				.values()
+				.filter(|r| r.time >= time)
+				.map(|r| r.event.clone())
+				.collect()
 		})
 	}
 
-	pub fn gc(&mut self, min_time: Timestamp) {
-		for (_, ch_events) in self.events.iter_mut() {
-			ch_events.retain(|&t, _| t >= min_time);
+	/// Returns up to `limit` (at most [MAX_PAGE_SIZE]) events of a channel,
+	/// starting at the sequence number `cursor`.
+	pub fn events_page(&self, ch: &ChannelId, cursor: u64, limit: u64) -> EventPage {
+		let limit = limit.min(MAX_PAGE_SIZE) as usize;
+		let events: Vec<EventRecord> = self.events.get(ch).map_or(vec![], |events| {
+			events
+				.range(cursor..)
+				.take(limit)
+				.map(|(_, r)| r.clone())
+				.collect()
+		});
+		let next_cursor = events.last().map_or(cursor, |r| r.seq + 1);
+		EventPage {
+			events,
+			next_cursor,
+		}
+	}
+
+	/// Returns the latest stored event of each channel.
+	pub fn heads(&self) -> impl Iterator<Item = &EventRecord> {
+		self.events
+			.values()
+			.filter_map(|events| events.values().next_back())
+	}
+
+	/// Returns the latest stored event of a channel.
+	pub fn head(&self, ch: &ChannelId) -> Option<EventRecord> {
+		self.events
+			.get(ch)
+			.and_then(|events| events.values().next_back().cloned())
+	}
+
+	/// Returns up to `limit` (at most [MAX_PAGE_SIZE]) events of all channels,
+	/// starting at the sequence number `cursor`.
+	pub fn all_events(&self, cursor: u64, limit: u64) -> EventPage {
+		let limit = limit.min(MAX_PAGE_SIZE) as usize;
+		let events: Vec<EventRecord> = self
+			.index
+			.range(cursor..)
+			.take(limit)
+			.map(|(seq, ch)| self.events[ch][seq].clone())
+			.collect();
+		let next_cursor = events.last().map_or(cursor, |r| r.seq + 1);
+		EventPage {
+			events,
+			next_cursor,
+		}
+	}
+
+	/// Records participants of a channel, so that its events are returned as
+	/// theirs even if no event names them.
+	pub fn add_members(&mut self, ch: &ChannelId, participants: &[L2Account]) {
+		let members = self.members.entry(ch.clone()).or_default();
+		for who in participants {
+			if !members.contains(who) {
+				members.push(who.clone());
+			}
+		}
+	}
+
+	/// Returns the recorded participants of a channel.
+	pub fn members(&self, ch: &ChannelId) -> Vec<L2Account> {
+		self.members.get(ch).cloned().unwrap_or_default()
+	}
+
+	/// Forgets the participants of a channel that has no stored events, e.g.,
+	/// because its events are forwarded to the event canister. The participants
+	/// of channels with stored events are forgotten once these are garbage
+	/// collected.
+	pub fn forget_members(&mut self, ch: &ChannelId) {
+		if !self.events.contains_key(ch) {
+			self.members.remove(ch);
+		}
+	}
+
+	/// Returns up to `limit` (at most [MAX_PAGE_SIZE]) events of the channels
+	/// in which the given participant takes part, starting at
+	/// the sequence number `cursor`. Scans at most [MAX_SCAN_SIZE] events, so
+	/// a page may be incomplete even if further matching events exist.
+	pub fn participant_events(&self, who: &L2Account, cursor: u64, limit: u64) -> EventPage {
+		let limit = limit.min(MAX_PAGE_SIZE) as usize;
+		let mut events = vec![];
+		let mut next_cursor = cursor;
+		for (seq, ch) in self.index.range(cursor..).take(MAX_SCAN_SIZE as usize) {
+			if events.len() == limit {
+				break;
+			}
+			next_cursor = seq + 1;
+			if self.members.get(ch).map_or(false, |m| m.contains(who)) {
+				events.push(self.events[ch][seq].clone());
+			}
+		}
+		EventPage {
+			events,
+			next_cursor,
+		}
+	}
+
+	/// Removes the events of concluded channels that were registered before
+	/// the given time. Events of open or disputed channels are kept. Channels
+	/// without remaining events are forgotten entirely. Returns the channels
+	/// whose events were removed.
+	pub fn gc(&mut self, min_time: Timestamp) -> Vec<ChannelId> {
+		let index = &mut self.index;
+		let mut collected = vec![];
+		for (ch, ch_events) in self.events.iter_mut() {
+			if self.concluded.contains(ch) {
+				let len = ch_events.len();
+				ch_events.retain(|seq, r| {
+					let keep = r.time >= min_time;
+					if !keep {
+						index.remove(seq);
+					}
+					keep
+				});
+				if ch_events.len() != len {
+					collected.push(ch.clone());
+				}
+			}
+		}
+		self.events.retain(|_, events| !events.is_empty());
+		for ch in collected.iter() {
+			if !self.events.contains_key(ch) {
+				self.concluded.remove(ch);
+				self.members.remove(ch);
+			}
+		}
+		collected
+	}
+
+	/// Garbage collects the events older than the given retention period, if
+	/// the last collection was at least [GC_INTERVAL] ago. Returns the
+	/// channels whose events were removed.
+	pub fn collect_garbage(&mut self, now: Timestamp, retention: Duration) -> Vec<ChannelId> {
+		if now < self.next_gc {
+			return vec![];
+		}
+		self.next_gc = now + GC_INTERVAL;
+		self.gc(now.saturating_sub(retention))
+	}
+
+	pub fn metrics(&self) -> EventMetrics {
+		let records = self.events.values().flat_map(|events| events.values());
+		EventMetrics {
+			retained: records.clone().count() as u64,
+			oldest: records.map(|r| r.time).min(),
 		}
-		self.events.retain(|_, events| !events.is_empty())
 	}
 
 	pub fn new() -> Self {
 		Self {
 			events: Default::default(),
+			index: Default::default(),
+			members: Default::default(),
+			next_seq: 0,
+			concluded: Default::default(),
+			next_gc: 0,
+		}
+	}
+}
+
+impl RPCEventRegisterer {
+	pub fn new(event_canister: Principal) -> Self {
+		Self { event_canister }
+	}
+
+	/// Forwards the queued events to the event canister in order, until the
+	/// queue is empty or a call fails, in which case the remaining events are
+	/// forwarded by the next flush. Does nothing if another call is already
+	/// forwarding, as that call also forwards the newly queued events.
+	pub async fn flush(&self) {
+		loop {
+			let next = OUTBOX.write().unwrap().begin(ic_cdk::api::time());
+			let (seq, time, ch, e, members) = match next {
+				Some(next) => next,
+				None => return,
+			};
+			let result: ic_cdk::api::call::CallResult<()> = ic_cdk::call(
+				self.event_canister,
+				"register_event",
+				(seq, ch, time, e, members),
+			)
+			.await;
+			let forwarded = result.is_ok();
+			OUTBOX.write().unwrap().finish(seq, forwarded);
+			if !forwarded {
+				return;
+			}
+		}
+	}
+}
+
+impl Outbox {
+	/// Queues an event to be forwarded along with its channel's participants.
+	pub fn push(&mut self, time: Timestamp, ch: ChannelId, e: Event, members: Vec<L2Account>) {
+		self.queue.push_back((self.next_seq, time, ch, e, members));
+		self.next_seq += 1;
+	}
+
+	/// Returns the next event to forward and marks it as being sent, unless
+	/// the outbox is empty or a call started less than [SEND_TIMEOUT] ago is
+	/// still forwarding.
+	pub fn begin(
+		&mut self,
+		now: Timestamp,
+	) -> Option<(u64, Timestamp, ChannelId, Event, Vec<L2Account>)> {
+		if matches!(self.sending, Some(start) if now < start + SEND_TIMEOUT) {
+			return None;
+		}
+		let next = self.queue.front().cloned()?;
+		self.sending = Some(now);
+		Some(next)
+	}
+
+	/// Records whether the event with the given sequence number returned by
+	/// [Outbox::begin] was forwarded, in which case it and all earlier events
+	/// are removed from the queue.
+	pub fn finish(&mut self, seq: u64, forwarded: bool) {
+		self.sending = None;
+		if forwarded {
+			while matches!(self.queue.front(), Some((s, ..)) if *s <= seq) {
+				self.queue.pop_front();
+			}
 		}
 	}
 }
 
 impl CanisterState {
-	pub fn new(perun_canister: Principal) -> Self {
+	pub fn new(perun_canister: Principal, retention: Option<Duration>) -> Self {
 		Self {
 			perun_canister: perun_canister,
+			retention,
+			last_seq: None,
 			imple: LocalEventRegisterer::new(),
 		}
 	}
 
+	/// Stores an event of a channel along with the channel's participants.
+	/// Only the Perun canister may register events, so that a misconfigured
+	/// Perun canister keeps its events queued instead of losing them. Events
+	/// have to be registered in the order of the Perun canister's sequence
+	/// numbers. Events that were already stored are ignored, as the Perun
+	/// canister sends them again if the reply to their call got lost.
+	pub fn register_event(
+		&mut self,
+		caller: &Principal,
+		seq: u64,
+		time: Timestamp,
+		ch: ChannelId,
+		e: Event,
+		participants: &[L2Account],
+	) -> Result<()> {
+		require!(caller == &self.perun_canister, Authentication);
+		if let Some(last) = self.last_seq {
+			if seq <= last {
+				return Ok(());
+			}
+			require!(seq == last + 1, InvalidInput);
+		}
+		self.last_seq = Some(seq);
+		self.imple.add_members(&ch, participants);
+		self.imple.push(time, ch, e);
+		Ok(())
+	}
+
+	/// Garbage collects the stored events according to the retention period,
+	/// see [LocalEventRegisterer::collect_garbage].
+	pub fn collect_garbage(&mut self, now: Timestamp) {
+		if let Some(retention) = self.retention {
+			self.imple.collect_garbage(now, retention);
+		}
+	}
+
+	pub fn metrics(&self) -> EventMetrics {
+		self.imple.metrics()
+	}
+
 	pub fn events_after(&self, ch: &ChannelId, time: Timestamp) -> Vec<Event> {
 		self.imple.events_after(ch, time)
 	}
+
+	pub fn events_page(&self, ch: &ChannelId, cursor: u64, limit: u64) -> EventPage {
+		self.imple.events_page(ch, cursor, limit)
+	}
+
+	pub fn all_events(&self, cursor: u64, limit: u64) -> EventPage {
+		self.imple.all_events(cursor, limit)
+	}
+
+	pub fn participant_events(&self, who: &L2Account, cursor: u64, limit: u64) -> EventPage {
+		self.imple.participant_events(who, cursor, limit)
+	}
 }
@@ -12,21 +12,31 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
-use crate::types::Amount;
+use crate::error::{Error, Result as CanisterResult};
+use crate::types::{Amount, Asset};
 use async_trait::async_trait;
 use ic_cdk::export::candid::{CandidType, Deserialize};
 use ic_cdk::export::Principal;
 use ic_ledger_types::{
-	query_archived_blocks, query_blocks, AccountIdentifier, Block, GetBlocksArgs, Operation,
-	Transaction, DEFAULT_SUBACCOUNT,
+	account_balance, query_archived_blocks, query_blocks, AccountBalanceArgs, AccountIdentifier,
+	Block, GetBlocksArgs, Operation, Subaccount, Timestamp, Tokens, Transaction, TransferArgs,
+	TransferError, DEFAULT_SUBACCOUNT,
 };
 use std::collections::{BTreeMap, BTreeSet};
 
 pub const MAINNET_ICP_LEDGER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+/// The ICP ledger's transfer fee in e8s.
+pub const DEFAULT_FEE_E8S: u64 = 10_000;
 
 pub type Memo = u64;
 pub type BlockHeight = u64;
 
+/// The memo of the canister's own transfers that sweep a deposit address into
+/// its default account. As outside transfers may carry the same memo, sweeps
+/// are recognised by their source account instead, see
+/// [crate::CanisterState::record_icp_tx].
+pub const SWEEP_MEMO: Memo = 0;
+
 /// ICP token handling errors.
 #[derive(PartialEq, Eq, CandidType, Deserialize, Debug)]
 pub enum ICPReceiverError {
@@ -42,62 +52,127 @@ impl std::fmt::Display for ICPReceiverError {
 	}
 }
 
-/// ICP transaction receiver for receiving and tracking payments for separate purposes.
+/// Reasons why a payout via the ledger did not succeed.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum PayoutError {
+	/// The ledger rejected the transfer, so no funds were moved.
+	Rejected,
+	/// The ledger could not be reached or its response was lost, so the
+	/// transfer may or may not have been executed.
+	Unknown,
+	/// The transfer's creation time is outside the ledger's deduplication
+	/// window, so the ledger did not execute it, but cannot tell whether it
+	/// was executed before.
+	Expired,
+}
+
+/// Transaction receiver for receiving and tracking payments of multiple assets
+/// for separate purposes.
 pub struct Receiver<Q: TXQuerier> {
 	tx_querier: Q,
 	my_account: AccountIdentifier,
-	known_txs: BTreeSet<BlockHeight>, // set of block heights
-	unspent: BTreeMap<Memo, Amount>,  // received tokens per memo
+	known_txs: BTreeSet<(Asset, BlockHeight)>, // set of block heights per ledger
+	unspent: BTreeMap<(Asset, Memo), Amount>,  // received tokens per asset and memo
+}
+
+/// The persistent part of a [Receiver], which has to be preserved across
+/// canister upgrades.
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct ReceiverState {
+	known_txs: BTreeSet<(Asset, BlockHeight)>,
+	unspent: BTreeMap<(Asset, Memo), Amount>,
 }
 
-/// ICP transaction querier.
+/// Ledger transaction querier.
 #[async_trait]
 pub trait TXQuerier {
-	/// Allows the
+	/// Queries a transaction from the given asset's ledger.
 	async fn query_tx(
 		&self,
+		asset: &Asset,
 		block_height: BlockHeight,
 	) -> Result<TransactionNotification, ICPReceiverError>;
 }
 
-/// Mocked ICP transaction querier for simulation and testing purposes.
+/// Pays out tokens from the canister's ledger account and collects deposits
+/// from its subaccounts.
+#[async_trait]
+pub trait Payer {
+	/// Transfers the given amount to a principal's default account and returns
+	/// the block height of the transfer. The memo and creation time are passed
+	/// to the ledger, so that repeating a payout with the same arguments is
+	/// deduplicated by the ledger and returns the original transfer's block.
+	async fn pay(
+		&self,
+		to: Principal,
+		amount: Amount,
+		memo: Memo,
+		created_at_time: crate::types::Timestamp,
+	) -> Result<BlockHeight, PayoutError>;
+
+	/// Returns the balance of one of the canister's subaccounts.
+	async fn balance(&self, subaccount: Subaccount) -> CanisterResult<Amount>;
+
+	/// Transfers the given amount from one of the canister's subaccounts to
+	/// its default account with the [SWEEP_MEMO] and returns the block height
+	/// of the transfer. Like payouts, repeating a sweep with the same
+	/// arguments is deduplicated by the ledger.
+	async fn sweep(
+		&self,
+		subaccount: Subaccount,
+		amount: Amount,
+		created_at_time: crate::types::Timestamp,
+	) -> Result<BlockHeight, PayoutError>;
+}
+
+/// Mocked transaction querier for testing purposes. Not part of canister
+/// builds.
+#[cfg(not(target_family = "wasm"))]
 #[derive(Default)]
 pub struct MockTXQuerier {
-	txs: BTreeMap<BlockHeight, TransactionNotification>,
+	txs: BTreeMap<(Asset, BlockHeight), TransactionNotification>,
 }
 
+#[cfg(not(target_family = "wasm"))]
 #[async_trait]
 impl TXQuerier for MockTXQuerier {
 	async fn query_tx(
 		&self,
+		asset: &Asset,
 		block_height: BlockHeight,
 	) -> Result<TransactionNotification, ICPReceiverError> {
 		self.txs
-			.get(&block_height)
+			.get(&(asset.clone(), block_height))
 			.cloned()
 			.ok_or(ICPReceiverError::FailedToQuery)
 	}
 }
 
+#[cfg(not(target_family = "wasm"))]
 impl MockTXQuerier {
 	/// Inserts a transaction so that it can be read via query_tx().
-	pub fn register_tx(&mut self, block_height: BlockHeight, tx: TransactionNotification) {
-		self.txs.insert(block_height, tx);
+	pub fn register_tx(
+		&mut self,
+		asset: Asset,
+		block_height: BlockHeight,
+		tx: TransactionNotification,
+	) {
+		self.txs.insert((asset, block_height), tx);
 	}
 }
 
 /// Real ICP transaction querier using inter-canister calls to the ICP ledger.
-pub struct CanisterTXQuerier {
-	icp_ledger: Principal,
-}
+#[derive(Default)]
+pub struct CanisterTXQuerier;
 
 #[async_trait]
 impl TXQuerier for CanisterTXQuerier {
 	async fn query_tx(
 		&self,
+		asset: &Asset,
 		block_height: BlockHeight,
 	) -> Result<TransactionNotification, ICPReceiverError> {
-		if let Some(block) = self.get_block_from_ledger(block_height).await {
+		if let Some(block) = Self::get_block_from_ledger(asset.ledger, block_height).await {
 			if let Some(tx) = TransactionNotification::from_tx(block.transaction) {
 				return Ok(tx);
 			} else {
@@ -109,24 +184,16 @@ impl TXQuerier for CanisterTXQuerier {
 }
 
 impl CanisterTXQuerier {
-	pub fn new(ledger: Principal) -> Self {
-		Self { icp_ledger: ledger }
-	}
-
-	/// Constructs a new canister TX querier targeting the mainnet ICP ledger canister.
-	pub fn for_mainnet() -> Self {
-		Self {
-			icp_ledger: Principal::from_text(MAINNET_ICP_LEDGER).unwrap(),
-		}
-	}
-
 	/// Queries a block from the ICP ledger's internal blockchain.
-	async fn get_block_from_ledger(&self, block_height: BlockHeight) -> Option<Block> {
+	async fn get_block_from_ledger(
+		icp_ledger: Principal,
+		block_height: BlockHeight,
+	) -> Option<Block> {
 		let args = GetBlocksArgs {
 			start: block_height,
 			length: 1,
 		};
-		if let Ok(result) = query_blocks(self.icp_ledger, args.clone()).await {
+		if let Ok(result) = query_blocks(icp_ledger, args.clone()).await {
 			if result.blocks.len() != 0 {
 				return result.blocks.first().cloned();
 			}
@@ -144,6 +211,107 @@ impl CanisterTXQuerier {
 	}
 }
 
+/// Real ICP payer using inter-canister calls to the ICP ledger.
+pub struct CanisterPayer {
+	icp_ledger: Principal,
+	fee: Amount,
+}
+
+#[async_trait]
+impl Payer for CanisterPayer {
+	async fn pay(
+		&self,
+		to: Principal,
+		amount: Amount,
+		memo: Memo,
+		created_at_time: crate::types::Timestamp,
+	) -> Result<BlockHeight, PayoutError> {
+		let amount = to_u64(&amount).ok_or(PayoutError::Rejected)?;
+		let fee = to_u64(&self.fee).ok_or(PayoutError::Rejected)?;
+		let args = TransferArgs {
+			memo: ic_ledger_types::Memo(memo),
+			amount: Tokens::from_e8s(amount),
+			fee: Tokens::from_e8s(fee),
+			from_subaccount: None,
+			to: AccountIdentifier::new(&to, &DEFAULT_SUBACCOUNT),
+			created_at_time: Some(Timestamp {
+				timestamp_nanos: created_at_time,
+			}),
+		};
+		match ic_ledger_types::transfer(self.icp_ledger, args).await {
+			Ok(Ok(block)) => Ok(block),
+			Ok(Err(TransferError::TxDuplicate { duplicate_of })) => Ok(duplicate_of),
+			Ok(Err(TransferError::TxTooOld { .. })) => Err(PayoutError::Expired),
+			Ok(Err(_)) => Err(PayoutError::Rejected),
+			Err(_) => Err(PayoutError::Unknown),
+		}
+	}
+
+	async fn balance(&self, subaccount: Subaccount) -> CanisterResult<Amount> {
+		let args = AccountBalanceArgs {
+			account: AccountIdentifier::new(&ic_cdk::id(), &subaccount),
+		};
+		match account_balance(self.icp_ledger, args).await {
+			Ok(tokens) => Ok(tokens.e8s().into()),
+			Err(_) => Err(Error::LedgerError),
+		}
+	}
+
+	async fn sweep(
+		&self,
+		subaccount: Subaccount,
+		amount: Amount,
+		created_at_time: crate::types::Timestamp,
+	) -> Result<BlockHeight, PayoutError> {
+		let to = AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT);
+		self.transfer(Some(subaccount), to, amount, created_at_time)
+			.await
+	}
+}
+
+impl CanisterPayer {
+	pub fn new(ledger: Principal, fee: Amount) -> Self {
+		Self {
+			icp_ledger: ledger,
+			fee,
+		}
+	}
+
+	/// Transfers funds from one of the canister's subaccounts to an account,
+	/// using the [SWEEP_MEMO].
+	async fn transfer(
+		&self,
+		from_subaccount: Option<Subaccount>,
+		to: AccountIdentifier,
+		amount: Amount,
+		created_at_time: crate::types::Timestamp,
+	) -> Result<BlockHeight, PayoutError> {
+		let amount = to_u64(&amount).ok_or(PayoutError::Rejected)?;
+		let fee = to_u64(&self.fee).ok_or(PayoutError::Rejected)?;
+		match ic_ledger_types::transfer(
+			self.icp_ledger,
+			TransferArgs {
+				memo: ic_ledger_types::Memo(SWEEP_MEMO),
+				amount: Tokens::from_e8s(amount),
+				fee: Tokens::from_e8s(fee),
+				from_subaccount,
+				to,
+				created_at_time: Some(Timestamp {
+					timestamp_nanos: created_at_time,
+				}),
+			},
+		)
+		.await
+		{
+			Ok(Ok(block)) => Ok(block),
+			Ok(Err(TransferError::TxDuplicate { duplicate_of })) => Ok(duplicate_of),
+			Ok(Err(TransferError::TxTooOld { .. })) => Err(PayoutError::Expired),
+			Ok(Err(_)) => Err(PayoutError::Rejected),
+			Err(_) => Err(PayoutError::Unknown),
+		}
+	}
+}
+
 impl<Q> Receiver<Q>
 where
 	Q: TXQuerier,
@@ -158,66 +326,132 @@ where
 		}
 	}
 
-	/// Verifies a transaction, and if it's valid and new, tracks its funds and
-	/// returns its amount.
-	pub async fn verify(
+	/// Queries a transaction from an asset's ledger.
+	pub async fn query(
+		&self,
+		asset: &Asset,
+		block_height: BlockHeight,
+	) -> std::result::Result<TransactionNotification, ICPReceiverError> {
+		self.tx_querier.query_tx(asset, block_height).await
+	}
+
+	/// Checks that a transaction was not processed yet, before querying it
+	/// from the ledger.
+	pub fn check(
+		&self,
+		asset: &Asset,
+		block_height: BlockHeight,
+	) -> std::result::Result<(), ICPReceiverError> {
+		if self.known_txs.contains(&(asset.clone(), block_height)) {
+			return Err(ICPReceiverError::DuplicateTransaction);
+		}
+		Ok(())
+	}
+
+	/// Records a transaction queried from an asset's ledger, and if it's valid
+	/// and was not processed meanwhile, tracks its funds and returns its
+	/// amount.
+	pub fn record(
 		&mut self,
+		asset: &Asset,
 		block_height: BlockHeight,
+		tx: TransactionNotification,
 	) -> std::result::Result<Amount, ICPReceiverError> {
-		if self.known_txs.contains(&block_height) {
+		if !self.known_txs.insert((asset.clone(), block_height)) {
 			return Err(ICPReceiverError::DuplicateTransaction);
 		}
+		if tx.to != self.my_account {
+			return Err(ICPReceiverError::Recipient);
+		}
+		*self
+			.unspent
+			.entry((asset.clone(), tx.memo))
+			.or_insert(0.into()) += tx.get_amount();
 
-		match self.tx_querier.query_tx(block_height).await {
-			Ok(tx) => {
-				if !self.known_txs.insert(block_height) {
-					return Err(ICPReceiverError::DuplicateTransaction);
-				}
-				if tx.to != self.my_account {
-					return Err(ICPReceiverError::Recipient);
-				}
-				*self.unspent.entry(tx.memo).or_insert(0.into()) += tx.get_amount();
+		Ok(tx.get_amount())
+	}
 
-				Ok(tx.get_amount())
-			}
-			Err(e) => Err(e),
+	/// Marks a transaction as processed without tracking its funds, because
+	/// they are credited otherwise. Returns whether the transaction is new.
+	pub fn claim(&mut self, asset: &Asset, block_height: BlockHeight) -> bool {
+		self.known_txs.insert((asset.clone(), block_height))
+	}
+
+	/// Returns a copy of the receiver's processed transactions and unspent
+	/// funds.
+	pub fn state(&self) -> ReceiverState {
+		ReceiverState {
+			known_txs: self.known_txs.clone(),
+			unspent: self.unspent.clone(),
 		}
 	}
 
-	/// Withdraws all funds from the requested memo.
-	pub fn drain(&mut self, memo: Memo) -> Amount {
-		return self.unspent.remove(&memo).unwrap_or(0.into()).into();
+	/// Replaces the receiver's processed transactions and unspent funds with a
+	/// previously saved state.
+	pub fn restore(&mut self, state: ReceiverState) {
+		self.known_txs = state.known_txs;
+		self.unspent = state.unspent;
 	}
 
-	/// Withdraws all funds from the requested memo if it is above a threshold.
-	pub fn drain_if_at_least(&mut self, memo: Memo, amount: Amount) -> Option<Amount> {
-		if let Some(sum) = self.unspent.get(&memo) {
+	/// Withdraws all funds of an asset from the requested memo.
+	pub fn drain(&mut self, asset: &Asset, memo: Memo) -> Amount {
+		return self
+			.unspent
+			.remove(&(asset.clone(), memo))
+			.unwrap_or(0.into())
+			.into();
+	}
+
+	/// Withdraws all funds of an asset from the requested memo if it is above a
+	/// threshold.
+	pub fn drain_if_at_least(
+		&mut self,
+		asset: &Asset,
+		memo: Memo,
+		amount: Amount,
+	) -> Option<Amount> {
+		let key = (asset.clone(), memo);
+		if let Some(sum) = self.unspent.get(&key) {
 			if sum >= &amount {
-				return self.unspent.remove(&memo).unwrap().into();
+				return self.unspent.remove(&key).unwrap().into();
 			}
 		}
 		None
 	}
 }
 
+/// Converts an amount or block index into its 64 bit representation as used by
+/// the ICP ledger. Returns nothing if the value does not fit.
+pub fn to_u64(amount: &Amount) -> Option<u64> {
+	let mut amount_str = amount.to_string();
+	amount_str.retain(|c| c != '_');
+	amount_str.parse::<u64>().ok()
+}
+
 /// Contents of a received transaction.
 #[derive(Clone, Hash, Debug, PartialEq, Eq, CandidType, Deserialize)]
 pub struct TransactionNotification {
+	/// The sending account of a transfer, nothing for mints.
+	pub from: Option<AccountIdentifier>,
 	pub to: AccountIdentifier,
 	pub amount: u64,
 	pub memo: Memo,
 }
 
 impl TransactionNotification {
-	/// Creates a transaction notification from an ICP ledger transaction. If the transaction is neither a transfer nor a mint, returns nothing.
+	/// Creates a transaction notification from an ICP ledger transaction. If
+	/// the transaction is neither a transfer nor a mint, returns nothing.
 	pub fn from_tx(tx: Transaction) -> Option<Self> {
 		if tx.operation.is_none() {
 			return None;
 		}
 
 		match tx.operation.unwrap() {
-			Operation::Transfer { to, amount, .. } => {
+			Operation::Transfer {
+				from, to, amount, ..
+			} => {
 				return Some(Self {
+					from: Some(from),
 					to: to,
 					amount: amount.e8s(),
 					memo: tx.memo.0,
@@ -225,6 +459,7 @@ impl TransactionNotification {
 			}
 			Operation::Mint { to, amount, .. } => {
 				return Some(Self {
+					from: None,
 					to: to,
 					amount: amount.e8s(),
 					memo: tx.memo.0,
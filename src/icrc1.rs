@@ -0,0 +1,404 @@
+//  Copyright 2022 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use crate::error::{Error, Result as CanisterResult};
+use crate::icp::{
+	to_u64, BlockHeight, ICPReceiverError, Memo, Payer, PayoutError, TXQuerier,
+	TransactionNotification, SWEEP_MEMO,
+};
+use crate::types::{Amount, Asset, Timestamp};
+use async_trait::async_trait;
+use candid::Func;
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use ic_cdk::export::Principal;
+use ic_ledger_types::{AccountIdentifier, Subaccount, DEFAULT_SUBACCOUNT};
+use serde_bytes::ByteBuf;
+
+/// An ICRC-1 account: an owner principal and an optional subaccount.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Account {
+	pub owner: Principal,
+	pub subaccount: Option<Subaccount>,
+}
+
+/// Arguments of the `icrc1_transfer` ledger method.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransferArg {
+	pub from_subaccount: Option<Subaccount>,
+	pub to: Account,
+	pub amount: Amount,
+	pub fee: Option<Amount>,
+	pub memo: Option<ByteBuf>,
+	pub created_at_time: Option<u64>,
+}
+
+/// Errors returned by the `icrc1_transfer` ledger method.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TransferError {
+	BadFee { expected_fee: Amount },
+	BadBurn { min_burn_amount: Amount },
+	InsufficientFunds { balance: Amount },
+	TooOld,
+	CreatedInFuture { ledger_time: u64 },
+	TemporarilyUnavailable,
+	Duplicate { duplicate_of: Amount },
+	GenericError { error_code: Amount, message: String },
+}
+
+/// Arguments of the ICRC-2 `icrc2_transfer_from` ledger method.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransferFromArgs {
+	pub spender_subaccount: Option<Subaccount>,
+	pub from: Account,
+	pub to: Account,
+	pub amount: Amount,
+	pub fee: Option<Amount>,
+	pub memo: Option<ByteBuf>,
+	pub created_at_time: Option<u64>,
+}
+
+/// Errors returned by the ICRC-2 `icrc2_transfer_from` ledger method.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TransferFromError {
+	BadFee { expected_fee: Amount },
+	BadBurn { min_burn_amount: Amount },
+	InsufficientFunds { balance: Amount },
+	InsufficientAllowance { allowance: Amount },
+	TooOld,
+	CreatedInFuture { ledger_time: u64 },
+	Duplicate { duplicate_of: Amount },
+	TemporarilyUnavailable,
+	GenericError { error_code: Amount, message: String },
+}
+
+/// Arguments of the `get_transactions` ledger and archive methods.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GetTransactionsRequest {
+	pub start: Amount,
+	pub length: Amount,
+}
+
+/// A minting operation.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Mint {
+	pub to: Account,
+	pub amount: Amount,
+	pub memo: Option<ByteBuf>,
+	pub created_at_time: Option<u64>,
+}
+
+/// A transfer operation.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Transfer {
+	pub from: Account,
+	pub to: Account,
+	pub amount: Amount,
+	pub fee: Option<Amount>,
+	pub memo: Option<ByteBuf>,
+	pub created_at_time: Option<u64>,
+}
+
+/// A transaction as returned by `get_transactions`. Operations that cannot
+/// credit the canister (burns, approvals) are not decoded.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Transaction {
+	pub kind: String,
+	pub mint: Option<Mint>,
+	pub transfer: Option<Transfer>,
+	pub timestamp: u64,
+}
+
+/// A range of transactions stored in an archive canister.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ArchivedRange {
+	pub start: Amount,
+	pub length: Amount,
+	pub callback: QueryArchiveFn,
+}
+
+/// Result of the `get_transactions` ledger method.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GetTransactionsResponse {
+	pub log_length: Amount,
+	pub first_index: Amount,
+	pub transactions: Vec<Transaction>,
+	pub archived_transactions: Vec<ArchivedRange>,
+}
+
+/// Result of an archive canister's `get_transactions` method.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransactionRange {
+	pub transactions: Vec<Transaction>,
+}
+
+/// Reference to an archive canister's query method.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(transparent)]
+pub struct QueryArchiveFn(Func);
+
+impl CandidType for QueryArchiveFn {
+	fn _ty() -> candid::types::Type {
+		candid::types::Type::Func(candid::types::Function {
+			modes: vec![candid::parser::types::FuncMode::Query],
+			args: vec![GetTransactionsRequest::ty()],
+			rets: vec![TransactionRange::ty()],
+		})
+	}
+
+	fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+	where
+		S: candid::types::Serializer,
+	{
+		self.0.idl_serialize(serializer)
+	}
+}
+
+impl Account {
+	/// Returns the ICP-style account identifier of the account, which is used
+	/// to compare recipients regardless of the token standard.
+	pub fn identifier(&self) -> AccountIdentifier {
+		AccountIdentifier::new(
+			&self.owner,
+			self.subaccount.as_ref().unwrap_or(&DEFAULT_SUBACCOUNT),
+		)
+	}
+}
+
+impl Transaction {
+	/// Creates a transaction notification from an ICRC-1 transaction. If the
+	/// transaction is neither a transfer nor a mint, or its memo is not a
+	/// big-endian encoded 64 bit integer, returns nothing.
+	pub fn notification(self) -> Option<TransactionNotification> {
+		let (from, to, amount, memo) = match (self.transfer, self.mint) {
+			(Some(t), _) => (Some(t.from.identifier()), t.to, t.amount, t.memo),
+			(None, Some(m)) => (None, m.to, m.amount, m.memo),
+			(None, None) => return None,
+		};
+
+		Some(TransactionNotification {
+			from,
+			to: to.identifier(),
+			amount: to_u64(&amount)?,
+			memo: decode_memo(memo)?,
+		})
+	}
+}
+
+/// Decodes an ICRC-1 memo into a Perun funding memo. A missing memo is treated
+/// as 0.
+fn decode_memo(memo: Option<ByteBuf>) -> Option<Memo> {
+	match memo {
+		None => Some(0),
+		Some(bytes) => Some(Memo::from_be_bytes(bytes.as_slice().try_into().ok()?)),
+	}
+}
+
+/// Encodes a Perun funding memo as ICRC-1 memo.
+pub fn encode_memo(memo: Memo) -> ByteBuf {
+	ByteBuf::from(memo.to_be_bytes().to_vec())
+}
+
+/// Transfers funds from an account that approved the canister as spender to
+/// the canister's default account, using the ICRC-2 `icrc2_transfer_from`
+/// ledger method. The funding's memo and the creation time are attached to the
+/// transfer, so that repeating it with the same arguments is deduplicated by
+/// the ledger. Returns the block height of the transfer, or of the original
+/// transfer if it is a duplicate.
+pub async fn transfer_from(
+	ledger: Principal,
+	from: Principal,
+	to: Principal,
+	amount: Amount,
+	fee: Amount,
+	memo: Memo,
+	created_at_time: Timestamp,
+) -> CanisterResult<BlockHeight> {
+	let args = TransferFromArgs {
+		spender_subaccount: None,
+		from: Account {
+			owner: from,
+			subaccount: None,
+		},
+		to: Account {
+			owner: to,
+			subaccount: None,
+		},
+		amount,
+		fee: Some(fee),
+		memo: Some(encode_memo(memo)),
+		created_at_time: Some(created_at_time),
+	};
+	let result: Result<(Result<Amount, TransferFromError>,), _> =
+		ic_cdk::call(ledger, "icrc2_transfer_from", (args,)).await;
+	match result {
+		Ok((Ok(block),))
+		| Ok((Err(TransferFromError::Duplicate {
+			duplicate_of: block,
+		}),)) => to_u64(&block).ok_or(Error::LedgerError),
+		_ => Err(Error::LedgerError),
+	}
+}
+
+/// ICRC-1 transaction querier using inter-canister calls to the token's
+/// ledger.
+#[derive(Default)]
+pub struct CanisterTXQuerier;
+
+#[async_trait]
+impl TXQuerier for CanisterTXQuerier {
+	async fn query_tx(
+		&self,
+		asset: &Asset,
+		block_height: BlockHeight,
+	) -> Result<TransactionNotification, ICPReceiverError> {
+		match Self::get_transaction(asset.ledger, block_height).await {
+			Some(tx) => tx.notification().ok_or(ICPReceiverError::TransactionType),
+			None => Err(ICPReceiverError::FailedToQuery),
+		}
+	}
+}
+
+impl CanisterTXQuerier {
+	/// Queries a transaction from the ledger or, if it was already archived,
+	/// from the responsible archive canister.
+	async fn get_transaction(ledger: Principal, index: BlockHeight) -> Option<Transaction> {
+		let args = GetTransactionsRequest {
+			start: index.into(),
+			length: 1u64.into(),
+		};
+		let (result,): (GetTransactionsResponse,) =
+			ic_cdk::call(ledger, "get_transactions", (args.clone(),))
+				.await
+				.ok()?;
+		if result.first_index == Amount::from(index) && !result.transactions.is_empty() {
+			return result.transactions.into_iter().next();
+		}
+
+		let index = Amount::from(index);
+		let archive = result
+			.archived_transactions
+			.into_iter()
+			.find(|a| a.start <= index && index < a.start.clone() + a.length.clone())?;
+		let (range,): (TransactionRange,) = ic_cdk::call(
+			archive.callback.0.principal,
+			&archive.callback.0.method,
+			(args,),
+		)
+		.await
+		.ok()?;
+		range.transactions.into_iter().next()
+	}
+}
+
+/// ICRC-1 payer using `icrc1_transfer` calls to the token's ledger.
+pub struct CanisterPayer {
+	ledger: Principal,
+	fee: Amount,
+}
+
+#[async_trait]
+impl Payer for CanisterPayer {
+	async fn pay(
+		&self,
+		to: Principal,
+		amount: Amount,
+		memo: Memo,
+		created_at_time: Timestamp,
+	) -> Result<BlockHeight, PayoutError> {
+		let args = TransferArg {
+			from_subaccount: None,
+			to: Account {
+				owner: to,
+				subaccount: None,
+			},
+			amount,
+			fee: Some(self.fee.clone()),
+			memo: Some(encode_memo(memo)),
+			created_at_time: Some(created_at_time),
+		};
+		let result: Result<(Result<Amount, TransferError>,), _> =
+			ic_cdk::call(self.ledger, "icrc1_transfer", (args,)).await;
+		let block = match result {
+			Ok((Ok(block),)) => block,
+			Ok((Err(TransferError::Duplicate { duplicate_of }),)) => duplicate_of,
+			Ok((Err(TransferError::TooOld),)) => return Err(PayoutError::Expired),
+			Ok((Err(_),)) => return Err(PayoutError::Rejected),
+			Err(_) => return Err(PayoutError::Unknown),
+		};
+		// The transfer was executed, but its block cannot be reported.
+		to_u64(&block).ok_or(PayoutError::Unknown)
+	}
+
+	async fn balance(&self, subaccount: Subaccount) -> CanisterResult<Amount> {
+		let account = Account {
+			owner: ic_cdk::id(),
+			subaccount: Some(subaccount),
+		};
+		let result: Result<(Amount,), _> =
+			ic_cdk::call(self.ledger, "icrc1_balance_of", (account,)).await;
+		match result {
+			Ok((balance,)) => Ok(balance),
+			Err(_) => Err(Error::LedgerError),
+		}
+	}
+
+	async fn sweep(
+		&self,
+		subaccount: Subaccount,
+		amount: Amount,
+		created_at_time: Timestamp,
+	) -> Result<BlockHeight, PayoutError> {
+		let to = Account {
+			owner: ic_cdk::id(),
+			subaccount: None,
+		};
+		self.transfer(Some(subaccount), to, amount, created_at_time)
+			.await
+	}
+}
+
+impl CanisterPayer {
+	pub fn new(ledger: Principal, fee: Amount) -> Self {
+		Self { ledger, fee }
+	}
+
+	/// Transfers funds from one of the canister's subaccounts to an account,
+	/// using the [SWEEP_MEMO].
+	async fn transfer(
+		&self,
+		from_subaccount: Option<Subaccount>,
+		to: Account,
+		amount: Amount,
+		created_at_time: Timestamp,
+	) -> Result<BlockHeight, PayoutError> {
+		let args = TransferArg {
+			from_subaccount,
+			to,
+			amount,
+			fee: Some(self.fee.clone()),
+			memo: Some(encode_memo(SWEEP_MEMO)),
+			created_at_time: Some(created_at_time),
+		};
+		let result: Result<(Result<Amount, TransferError>,), _> =
+			ic_cdk::call(self.ledger, "icrc1_transfer", (args,)).await;
+		let block = match result {
+			Ok((Ok(block),)) => block,
+			Ok((Err(TransferError::Duplicate { duplicate_of }),)) => duplicate_of,
+			Ok((Err(TransferError::TooOld),)) => return Err(PayoutError::Expired),
+			Ok((Err(_),)) => return Err(PayoutError::Rejected),
+			Err(_) => return Err(PayoutError::Unknown),
+		};
+		to_u64(&block).ok_or(PayoutError::Unknown)
+	}
+}
@@ -0,0 +1,153 @@
+//  Copyright 2022 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use crate::error::Result as CanisterResult;
+use crate::icp::{
+	BlockHeight, ICPReceiverError, Memo, Payer, PayoutError, TXQuerier, TransactionNotification,
+};
+use crate::types::{Amount, Asset, Funding, SweepEntry, Timestamp, TokenStandard};
+use crate::{icp, icrc1};
+use async_trait::async_trait;
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use ic_cdk::export::Principal;
+use ic_ledger_types::{AccountIdentifier, Subaccount};
+
+/// The ledger account to which a funding's deposits have to be transferred.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct DepositAddress {
+	/// The ICRC-1 account, owned by the canister.
+	pub account: icrc1::Account,
+	/// The same account as ICP ledger account identifier.
+	pub account_identifier: AccountIdentifier,
+}
+
+/// Transaction querier for ledgers of all supported token standards. Queries
+/// each asset's ledger according to its token standard.
+#[derive(Default)]
+pub struct LedgerTXQuerier;
+
+#[async_trait]
+impl TXQuerier for LedgerTXQuerier {
+	async fn query_tx(
+		&self,
+		asset: &Asset,
+		block_height: BlockHeight,
+	) -> Result<TransactionNotification, ICPReceiverError> {
+		match asset.standard {
+			TokenStandard::ICP => icp::CanisterTXQuerier.query_tx(asset, block_height).await,
+			TokenStandard::ICRC1 => icrc1::CanisterTXQuerier.query_tx(asset, block_height).await,
+		}
+	}
+}
+
+/// Payer for the ledger of any supported token standard.
+pub enum LedgerPayer {
+	ICP(icp::CanisterPayer),
+	ICRC1(icrc1::CanisterPayer),
+}
+
+#[async_trait]
+impl Payer for LedgerPayer {
+	async fn pay(
+		&self,
+		to: Principal,
+		amount: Amount,
+		memo: Memo,
+		created_at_time: Timestamp,
+	) -> Result<BlockHeight, PayoutError> {
+		match self {
+			Self::ICP(p) => p.pay(to, amount, memo, created_at_time).await,
+			Self::ICRC1(p) => p.pay(to, amount, memo, created_at_time).await,
+		}
+	}
+
+	async fn balance(&self, subaccount: Subaccount) -> CanisterResult<Amount> {
+		match self {
+			Self::ICP(p) => p.balance(subaccount).await,
+			Self::ICRC1(p) => p.balance(subaccount).await,
+		}
+	}
+
+	async fn sweep(
+		&self,
+		subaccount: Subaccount,
+		amount: Amount,
+		created_at_time: Timestamp,
+	) -> Result<BlockHeight, PayoutError> {
+		match self {
+			Self::ICP(p) => p.sweep(subaccount, amount, created_at_time).await,
+			Self::ICRC1(p) => p.sweep(subaccount, amount, created_at_time).await,
+		}
+	}
+}
+
+impl LedgerPayer {
+	/// Creates a payer for an asset's ledger, using the given transfer fee.
+	pub fn new(asset: &Asset, fee: Amount) -> Self {
+		match asset.standard {
+			TokenStandard::ICP => Self::ICP(icp::CanisterPayer::new(asset.ledger, fee)),
+			TokenStandard::ICRC1 => Self::ICRC1(icrc1::CanisterPayer::new(asset.ledger, fee)),
+		}
+	}
+}
+
+impl DepositAddress {
+	/// Returns the deposit address of a funding at the given canister.
+	pub fn new(canister: Principal, funding: &Funding) -> Self {
+		let account = icrc1::Account {
+			owner: canister,
+			subaccount: Some(funding.subaccount()),
+		};
+		Self {
+			account_identifier: account.identifier(),
+			account,
+		}
+	}
+}
+
+/// Prepares collecting the deposits of a funding by sweeping the funding's
+/// subaccount into the canister's default account. The ledger fee for the
+/// sweep is paid from the deposit. Returns the sweep to execute, or nothing if
+/// the balance does not exceed the fee.
+pub async fn prepare_sweep<P: Payer + Sync>(
+	payer: &P,
+	funding: &Funding,
+	fee: &Amount,
+	now: Timestamp,
+) -> CanisterResult<Option<SweepEntry>> {
+	let balance = payer.balance(funding.subaccount()).await?;
+	if &balance <= fee {
+		return Ok(None);
+	}
+	Ok(Some(SweepEntry {
+		amount: balance - fee.clone(),
+		created_at_time: now,
+	}))
+}
+
+/// Executes a sweep of a funding's subaccount. Repeating a sweep whose outcome
+/// is unknown with the same entry is deduplicated by the ledger.
+pub async fn sweep_deposit<P: Payer + Sync>(
+	payer: &P,
+	funding: &Funding,
+	sweep: &SweepEntry,
+) -> Result<BlockHeight, PayoutError> {
+	payer
+		.sweep(
+			funding.subaccount(),
+			sweep.amount.clone(),
+			sweep.created_at_time,
+		)
+		.await
+}
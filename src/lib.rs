@@ -12,9 +12,16 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
+pub mod app;
+pub mod certification;
 pub mod error;
 pub mod events;
 pub mod icp;
+pub mod icrc1;
+pub mod ledger;
+pub mod stable;
+pub mod subscriptions;
+pub mod tictactoe;
 pub mod types;
 
 // We don't need testing code in wasm output, only for tests and examples
@@ -26,58 +33,228 @@ mod tests;
 
 use ic_cdk::api::time as blocktime;
 use ic_cdk::export::Principal;
-use ic_ledger_types::{
-	AccountIdentifier, Memo, Tokens, TransferArgs, DEFAULT_FEE, DEFAULT_SUBACCOUNT,
-};
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::RwLock;
 
+use certification::{Certified, Witness};
 use error::*;
 use events::*;
+use icp::Payer;
 use types::*;
 
 lazy_static! {
-	static ref STATE: RwLock<CanisterState<icp::CanisterTXQuerier>> =
-		RwLock::new(CanisterState::new(
-			icp::CanisterTXQuerier::new(
-				Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").expect("parsing principal")
-			),
-			ic_cdk::id(),
-		));
+	static ref STATE: RwLock<CanisterState<ledger::LedgerTXQuerier>> =
+		RwLock::new(canister_state(Config::default()));
+}
+
+/// Creates the canister's state for a configuration, querying transactions from
+/// the ledgers of the channels' assets.
+fn canister_state(config: Config) -> CanisterState<ledger::LedgerTXQuerier> {
+	CanisterState::new(ledger::LedgerTXQuerier, ic_cdk::id(), config)
 }
 
 /// The canister's state. Contains all currently registered channels, as well as
 /// all deposits and withdrawable balances.
 pub struct CanisterState<Q: icp::TXQuerier> {
+	/// The canister's deployment configuration.
+	config: Config,
+	/// The canister's own principal, which all signatures are bound to.
+	principal: Principal,
 	icp_receiver: icp::Receiver<Q>,
 	/// Tracks all deposits for unregistered channels. For registered channels,
 	/// tracks withdrawable balances instead.
 	holdings: HashMap<Funding, Amount>,
 	/// Tracks all registered channels.
 	channels: HashMap<ChannelId, RegisteredState>,
+	/// The registered channel that locks funds for each sub-channel. Derived
+	/// from the registered states, so it is not part of the snapshot.
+	parents: HashMap<ChannelId, ChannelId>,
+	/// The parameters of all registered or announced channels.
+	params: HashMap<ChannelId, Params>,
+	/// The lifecycle phase of all channels that received deposits or were
+	/// registered. Other channels are in the funding phase.
+	phases: HashMap<ChannelId, ChannelPhase>,
+	/// The outcome of each announced channel's initial state, which its
+	/// holdings have to cover for the channel to be funded.
+	targets: HashMap<ChannelId, Vec<Amount>>,
+	/// Journal of the latest ledger withdrawal of each funding.
+	withdrawals: HashMap<Funding, WithdrawalEntry>,
+	/// Journal of the sweeps of deposit addresses whose outcome is not known
+	/// yet.
+	sweeps: HashMap<Funding, SweepEntry>,
+	/// The nonce of each funding's latest executed withdrawal request.
+	nonces: HashMap<Funding, u64>,
+	/// The pending channel settlements, ordered by time.
+	timeouts: BTreeSet<(Timestamp, ChannelId)>,
+	/// Withdrawals to be paid out automatically once a channel is settled.
+	payouts: HashMap<ChannelId, Vec<WithdrawalRequest>>,
+	/// The hash tree over the registered states, holdings and latest events,
+	/// whose root hash is certified.
+	certified: certification::CertifiedTree,
+}
+
+#[ic_cdk_macros::init]
+/// Configures the canister's ledger, fee, event canister and administrators.
+fn init(config: Config) {
+	*STATE.write().unwrap() = canister_state(config);
+	certify();
 }
 
+#[ic_cdk_macros::pre_upgrade]
+/// Writes the canister's state into stable memory before upgrading.
+fn pre_upgrade() {
+	stable::save(&stable::StableState {
+		canister: STATE.read().unwrap().snapshot(),
+		events: events::STATE.read().unwrap().clone(),
+		outbox: events::OUTBOX.read().unwrap().clone(),
+		subscriptions: subscriptions::STATE.read().unwrap().clone(),
+	});
+}
+
+#[ic_cdk_macros::post_upgrade]
+/// Restores the canister's state from stable memory after upgrading.
+fn post_upgrade() {
+	let state = match stable::restore() {
+		Ok(Some(state)) => state,
+		Ok(None) => return,
+		Err(e) => ic_cdk::trap(&format!("restoring stable memory: {}", e)),
+	};
+	let mut canister = canister_state(state.canister.config.clone());
+	canister.restore(state.canister);
+	for head in state.events.heads() {
+		canister.certify_event(head);
+	}
+	*STATE.write().unwrap() = canister;
+	*events::STATE.write().unwrap() = state.events;
+	*events::OUTBOX.write().unwrap() = state.outbox;
+	*subscriptions::STATE.write().unwrap() = state.subscriptions;
+	certify();
+}
+
+#[cfg(feature = "memo-deposits")]
 #[ic_cdk_macros::update]
-/// The user needs to call this with his transaction.
-async fn transaction_notification(block_height: u64) -> Result<Amount> {
-	STATE.write().unwrap().process_icp_tx(block_height).await
+/// The user needs to call this with his transaction and the asset whose
+/// ledger it was made on. Only exported in the legacy memo deposit mode, see
+/// [CanisterState::deposit_icp].
+async fn transaction_notification(asset: Asset, block_height: u64) -> Result<Amount> {
+	use icp::TXQuerier;
+	STATE.read().unwrap().check_icp_tx(&asset, block_height)?;
+	// The state is not locked while waiting for the ledger.
+	let tx = ledger::LedgerTXQuerier
+		.query_tx(&asset, block_height)
+		.await
+		.map_err(Error::ReceiverError)?;
+	STATE
+		.write()
+		.unwrap()
+		.record_icp_tx(&asset, block_height, tx)
 }
 
 #[ic_cdk_macros::update]
+/// Credits the funds transferred to a funding's deposit address (see
+/// `query_deposit_address`) to the funding. In the legacy memo deposit mode,
+/// funds that were transferred to the canister's default account with the
+/// funding's memo and registered via `transaction_notification` are credited
+/// as well. If the call fails because
+/// the outcome of the sweep of the deposit address is unknown, repeating it
+/// repeats the sweep with identical arguments, until the sweep left the
+/// ledger's deduplication window and has to be settled via `settle_sweep`.
+/// Does nothing if there are no funds to credit.
 async fn deposit(funding: Funding) -> Option<Error> {
-	STATE
+	deposit_impl(funding).await.err()
+}
+
+async fn deposit_impl(funding: Funding) -> Result<()> {
+	let fee = STATE
+		.read()
+		.unwrap()
+		.config()
+		.fee(&funding.asset)
+		.ok_or(Error::UnsupportedAsset)?;
+	let payer = ledger::LedgerPayer::new(&funding.asset, fee.clone());
+	// A sweep whose outcome is unknown is repeated with identical arguments,
+	// so that the ledger deduplicates it.
+	let pending = STATE.read().unwrap().pending_sweep(&funding);
+	let sweep = match pending {
+		Some(sweep) => Some(sweep),
+		None => ledger::prepare_sweep(&payer, &funding, &fee, blocktime()).await?,
+	};
+	let event = match sweep {
+		Some(sweep) => {
+			let sweep = STATE.write().unwrap().begin_sweep(&funding, sweep);
+			let result = ledger::sweep_deposit(&payer, &funding, &sweep).await;
+			STATE
+				.write()
+				.unwrap()
+				.finish_sweep(&funding, &sweep, result)?
+		}
+		None => STATE
+			.write()
+			.unwrap()
+			.deposit_icp(funding.clone(), Amount::default())?,
+	};
+	if let Some(event) = event {
+		register_event(funding.channel, event).await;
+	}
+	Ok(())
+}
+
+#[ic_cdk_macros::update]
+/// Deposits funds for a channel participant by pulling them from the caller's
+/// ICRC-2 allowance. The caller has to approve the canister as spender of at
+/// least the amount plus the ledger fee beforehand. The funds are credited in
+/// the same call, so no transaction notification is needed. The creation time
+/// is passed to the ledger: if the call's outcome is unknown, repeating it
+/// with the same arguments within the ledger's deduplication window neither
+/// pulls nor credits the funds twice.
+async fn deposit_from(
+	funding: Funding,
+	amount: Amount,
+	created_at_time: Timestamp,
+) -> Option<Error> {
+	let fee = match STATE.read().unwrap().config().fee(&funding.asset) {
+		Some(fee) => fee,
+		None => return Some(Error::UnsupportedAsset),
+	};
+	let block = match icrc1::transfer_from(
+		funding.asset.ledger,
+		ic_cdk::caller(),
+		ic_cdk::id(),
+		amount.clone(),
+		fee,
+		funding.memo(),
+		created_at_time,
+	)
+	.await
+	{
+		Ok(block) => block,
+		Err(e) => return Some(e),
+	};
+
+	let result = STATE
 		.write()
 		.unwrap()
-		.deposit_icp(blocktime(), funding)
-		.await
-		.err()
+		.deposit_pulled(funding.clone(), amount, block);
+	certify();
+	match result {
+		Ok(Some(event)) => {
+			register_event(funding.channel, event).await;
+			None
+		}
+		Ok(None) => None,
+		Err(e) => Some(e),
+	}
 }
 
+#[cfg(feature = "test-endpoints")]
 #[ic_cdk_macros::update]
-/// Only used for tests.
+/// Credits funds without receiving them. Only used for tests.
 fn deposit_mocked(funding: Funding, amount: Amount) -> Option<Error> {
-	STATE.write().unwrap().deposit(funding, amount).err()
+	let result = STATE.write().unwrap().deposit(funding, amount);
+	certify();
+	result.err()
 }
 
 #[ic_cdk_macros::update]
@@ -85,91 +262,490 @@ fn deposit_mocked(funding: Funding, amount: Amount) -> Option<Error> {
 /// will have to reply with a call to 'dispute' within the channel's challenge
 /// duration to register a more recent channel state if exists. After the
 /// challenge duration elapsed, the channel will be marked as settled.
-fn dispute(params: Params, state: FullySignedState) -> Option<Error> {
-	STATE
+async fn dispute(params: Params, state: FullySignedState) -> Option<Error> {
+	let channel = state.state.channel.clone();
+	let result = STATE.write().unwrap().dispute(params, state, blocktime());
+	if let Err(e) = result {
+		return Some(e);
+	}
+	let registered = STATE.read().unwrap().state(&channel).unwrap();
+	register_event(channel, Event::Disputed(registered)).await;
+	None
+}
+
+#[ic_cdk_macros::update]
+/// Advances a disputed app channel's state by a transition signed only by the
+/// participant whose turn it is, if the channel's app allows the transition.
+/// `old` has to be the currently registered state. Only possible once the
+/// dispute's challenge duration elapsed. Restarts the challenge duration, so
+/// that the other participants can respond in turn.
+async fn progress(params: Params, old: State, new: State, actor_sig: L2Signature) -> Option<Error> {
+	let channel = new.channel.clone();
+	let result = STATE
 		.write()
 		.unwrap()
-		.dispute(params, state, blocktime())
-		.err()
+		.progress(params, old, new, actor_sig, blocktime());
+	if let Err(e) = result {
+		return Some(e);
+	}
+	let registered = STATE.read().unwrap().state(&channel).unwrap();
+	register_event(channel, Event::Progressed(registered)).await;
+	None
 }
 
 #[ic_cdk_macros::update]
 /// Settles a finalized channel and makes its final funds distribution
-/// withdrawable.
+/// withdrawable. The conclusion event is registered when the channel's
+/// settlement is processed.
 fn conclude(params: Params, state: FullySignedState) -> Option<Error> {
+	let result = STATE.write().unwrap().conclude(params, state, blocktime());
+	certify();
+	result.err()
+}
+
+#[ic_cdk_macros::update]
+/// Concludes a disputed channel whose challenge duration passed and registers
+/// its conclusion. Such channels are also concluded by the heartbeat, this
+/// allows to conclude them without waiting for it.
+async fn conclude_dispute(channel: ChannelId) -> Option<Error> {
+	let result = STATE
+		.write()
+		.unwrap()
+		.conclude_dispute(&channel, blocktime());
+	certify();
+	let payouts = match result {
+		Ok(payouts) => payouts,
+		Err(e) => return Some(e),
+	};
+	finish_settlement(vec![channel], payouts).await;
+	None
+}
+
+#[ic_cdk_macros::update]
+/// Settles a channel that locks funds in sub-channels, and distributes the
+/// locked funds according to the sub-channels' final states, or their
+/// registered states if their disputes timed out. The channel's
+/// state has to be final, or the registered state of a channel whose dispute
+/// timed out.
+fn conclude_with_subchannels(
+	params: Params,
+	state: FullySignedState,
+	subchannels: Vec<SubChannel>,
+) -> Option<Error> {
+	let now = blocktime();
+	let result = STATE
+		.write()
+		.unwrap()
+		.conclude_with_subchannels(params, state, subchannels, now);
+	certify();
+	result.err()
+}
+
+#[ic_cdk_macros::update]
+/// Registers a signed withdrawal request to be paid out automatically once the
+/// channel is settled, i.e., concluded or its dispute timed out.
+fn register_payout(request: WithdrawalRequest, auth: L2Signature) -> Option<Error> {
 	STATE
 		.write()
 		.unwrap()
-		.conclude(params, state, blocktime())
+		.register_payout(request, auth, blocktime())
 		.err()
 }
 
+#[ic_cdk_macros::heartbeat]
+/// Settles all concluded channels and disputes whose challenge duration has
+/// passed: registers their conclusion and pays out the withdrawals registered
+/// for them. Also garbage collects the locally stored events periodically,
+/// forwards events that could not be forwarded to the event canister yet and
+/// notifies subscribers about new events, a limited number per heartbeat.
+async fn heartbeat() {
+	let retention = STATE.read().unwrap().config().event_retention;
+	if let Some(retention) = retention {
+		let mut events = events::STATE.write().unwrap();
+		let collected = events.collect_garbage(blocktime(), retention);
+		if !collected.is_empty() {
+			STATE
+				.write()
+				.unwrap()
+				.certify_collected_events(&events, &collected);
+			drop(events);
+			certify();
+		}
+	}
+	let due = subscriptions::STATE.write().unwrap().take_due(blocktime());
+	for (sub, delivery) in due {
+		ic_cdk::spawn(deliver(sub, delivery));
+	}
+
+	let event_canister = STATE.read().unwrap().config().event_canister;
+	if let Some(canister) = event_canister {
+		RPCEventRegisterer::new(canister).flush().await;
+	}
+
+	let (concluded, payouts) = STATE.write().unwrap().settle_expired(blocktime());
+	certify();
+	finish_settlement(concluded, payouts).await;
+}
+
+/// Notifies a subscriber about an event and records whether it replied.
+async fn deliver(sub: subscriptions::Subscription, delivery: subscriptions::Delivery) {
+	let args = (
+		delivery.channel.clone(),
+		delivery.time,
+		delivery.event.clone(),
+	);
+	let result: ic_cdk::api::call::CallResult<()> =
+		ic_cdk::call(sub.canister, &sub.method, args).await;
+	subscriptions::STATE
+		.write()
+		.unwrap()
+		.delivered(delivery, result.is_ok(), blocktime());
+}
+
+/// Registers the conclusion of settled channels and pays out the withdrawals
+/// registered for them.
+async fn finish_settlement(concluded: Vec<ChannelId>, payouts: Vec<(Funding, WithdrawalEntry)>) {
+	for channel in concluded {
+		register_event(channel, Event::Concluded).await;
+	}
+	for (funding, entry) in payouts {
+		// Failed payouts are recorded in the withdrawal journal.
+		let _ = execute_withdrawal(funding, entry).await;
+	}
+}
+
 #[ic_cdk_macros::update]
-/// Withdraws the specified participant's funds from a settled channel.
+/// Subscribes the calling canister's method to the events of a channel or
+/// participant. The call has to attach the subscription fee in cycles. The
+/// method is notified about each new event via a one-way call with the
+/// arguments `(ChannelId, Timestamp, Event)`. Notifications that cannot be
+/// sent are retried, and subscribers are unsubscribed after repeated failures.
+/// Returns the subscription's id.
+fn subscribe(topic: subscriptions::Topic, method: String) -> (Option<u64>, Option<Error>) {
+	let cycles = ic_cdk::api::call::msg_cycles_available();
+	let result =
+		subscriptions::STATE
+			.write()
+			.unwrap()
+			.subscribe(ic_cdk::caller(), topic, method, cycles);
+	if result.is_ok() {
+		ic_cdk::api::call::msg_cycles_accept(subscriptions::SUBSCRIPTION_FEE);
+	}
+	(result.as_ref().ok().cloned(), result.err())
+}
+
+#[ic_cdk_macros::update]
+/// Removes one of the calling canister's subscriptions.
+fn unsubscribe(id: u64) -> Option<Error> {
+	subscriptions::STATE
+		.write()
+		.unwrap()
+		.unsubscribe(ic_cdk::caller(), id)
+		.err()
+}
+
+#[ic_cdk_macros::update]
+/// Withdraws the specified participant's funds from a settled channel. The
+/// ledger's transfer fee is deducted from the withdrawn funds, so withdrawals
+/// that do not exceed the fee are rejected. If the outcome of a previous
+/// withdrawal's transfer is unknown, repeats that transfer instead, which the
+/// ledger deduplicates.
 async fn withdraw(
 	request: WithdrawalRequest,
 	auth: L2Signature,
-) -> (Option<icp::BlockHeight>, Option<Error>) {
+) -> (Option<WithdrawalReceipt>, Option<Error>) {
 	let result = withdraw_impl(request, auth).await;
 	(result.as_ref().ok().cloned(), result.err())
 }
 
+#[cfg(feature = "test-endpoints")]
 #[ic_cdk_macros::update]
-/// Withdraws the specified participant's funds from a settled channel.
+/// Withdraws the specified participant's funds from a settled channel without
+/// paying them out. Only used for tests.
 async fn withdraw_mocked(
 	request: WithdrawalRequest,
 	auth: L2Signature,
 ) -> (Option<Amount>, Option<Error>) {
 	let result = STATE.write().unwrap().withdraw(request, auth, blocktime());
+	certify();
+	(result.as_ref().ok().cloned(), result.err())
+}
+
+#[ic_cdk_macros::update]
+/// Withdraws a participant's funds from a settled channel to the caller, which
+/// has to be the principal bound to the participant by the channel's
+/// parameters. No layer-2 signature is needed. Otherwise behaves like
+/// `withdraw`.
+async fn withdraw_as_caller(funding: Funding) -> (Option<WithdrawalReceipt>, Option<Error>) {
+	let result = withdraw_as_caller_impl(funding).await;
 	(result.as_ref().ok().cloned(), result.err())
 }
 
-async fn withdraw_impl(request: WithdrawalRequest, auth: L2Signature) -> Result<icp::BlockHeight> {
-	let receiver = request.receiver.clone();
+async fn withdraw_as_caller_impl(funding: Funding) -> Result<WithdrawalReceipt> {
+	let entry = STATE.write().unwrap().begin_withdrawal_as(
+		funding.clone(),
+		ic_cdk::caller(),
+		blocktime(),
+	)?;
+	certify();
+	execute_withdrawal(funding, entry).await
+}
+
+async fn withdraw_impl(request: WithdrawalRequest, auth: L2Signature) -> Result<WithdrawalReceipt> {
 	let funding = request.funding.clone();
-	let amount = STATE
+	let entry = STATE
 		.write()
 		.unwrap()
-		.withdraw(request, auth, blocktime())?;
-	let mut amount_str = amount.to_string();
-	amount_str.retain(|c| c != '_');
-	let amount_u64 = amount_str.parse::<u64>().unwrap();
-
-	match ic_ledger_types::transfer(
-		Principal::from_text(icp::MAINNET_ICP_LEDGER).unwrap(),
-		TransferArgs {
-			memo: Memo(0),
-			amount: Tokens::from_e8s(amount_u64),
-			fee: DEFAULT_FEE,
-			from_subaccount: None,
-			to: AccountIdentifier::new(&receiver, &DEFAULT_SUBACCOUNT),
-			created_at_time: None,
-		},
-	)
-	.await
-	{
-		Ok(transfer_result) => match transfer_result {
-			Ok(block) => Ok(block.into()),
-			Err(_) => {
-				STATE.write().unwrap().deposit(funding, amount)?;
-				Err(Error::LedgerError)
+		.begin_withdrawal(request, auth, blocktime())?;
+	certify();
+	execute_withdrawal(funding, entry).await
+}
+
+/// Performs a journaled withdrawal's ledger transfer and records its outcome.
+async fn execute_withdrawal(funding: Funding, entry: WithdrawalEntry) -> Result<WithdrawalReceipt> {
+	let result = ledger::LedgerPayer::new(&funding.asset, entry.fee.clone())
+		.pay(
+			entry.receiver.clone(),
+			entry.amount.clone(),
+			funding.memo(),
+			entry.created_at_time,
+		)
+		.await;
+	let result = STATE.write().unwrap().finish_withdrawal(&funding, result);
+	certify();
+	let receipt = result?;
+	register_withdrawn(funding, entry.receiver, &receipt).await;
+	Ok(receipt)
+}
+
+#[ic_cdk_macros::update]
+/// Settles a pending withdrawal whose transfer left the ledger's deduplication
+/// window, so that repeating it cannot tell whether it was executed. One of
+/// the canister's administrators has to look the transfer up on the ledger and
+/// pass its block if it was executed, or nothing if it was not, which credits
+/// the withdrawn funds and fee back to the holdings.
+async fn settle_withdrawal(funding: Funding, block: Option<u64>) -> Option<Error> {
+	let result = STATE
+		.write()
+		.unwrap()
+		.settle_withdrawal(&ic_cdk::caller(), &funding, block);
+	certify();
+	match result {
+		Ok(Some(receipt)) => {
+			let receiver = STATE
+				.read()
+				.unwrap()
+				.query_withdrawal(&funding)
+				.unwrap()
+				.receiver;
+			register_withdrawn(funding, receiver, &receipt).await;
+			None
+		}
+		Ok(None) => None,
+		Err(e) => Some(e),
+	}
+}
+
+#[ic_cdk_macros::update]
+/// Settles a journaled sweep of a funding's deposit address that left the
+/// ledger's deduplication window, so that repeating it cannot tell whether it
+/// was executed. One of the canister's administrators has to look the sweep up
+/// on the ledger and pass its block if it was executed, which credits it, or
+/// nothing if it was not, so that the next deposit sweeps the address anew.
+async fn settle_sweep(funding: Funding, block: Option<u64>) -> Option<Error> {
+	let result = STATE
+		.write()
+		.unwrap()
+		.settle_sweep(&ic_cdk::caller(), &funding, block);
+	certify();
+	match result {
+		Ok(Some(event)) => {
+			register_event(funding.channel, event).await;
+			None
+		}
+		Ok(None) => None,
+		Err(e) => Some(e),
+	}
+}
+
+/// Registers the event of a completed withdrawal.
+async fn register_withdrawn(funding: Funding, receiver: Principal, receipt: &WithdrawalReceipt) {
+	let event = Event::Withdrawn {
+		who: funding.participant,
+		asset: funding.asset,
+		receiver,
+		amount: receipt.amount.clone(),
+		fee: receipt.fee.clone(),
+		block: receipt.block,
+	};
+	register_event(funding.channel, event).await;
+}
+
+/// Registers a channel event at the current time. The event is forwarded to
+/// the configured event canister, if any, and stored and certified locally
+/// otherwise. Events that cannot be forwarded right away are forwarded by the
+/// heartbeat. Subscribers are notified about it with the next heartbeat. The
+/// channel's participants are recorded in the local event log in either case,
+/// so that subscribers of participants are notified.
+async fn register_event(channel: ChannelId, event: Event) {
+	let (event_canister, withdrawn, params_members) = {
+		let state = STATE.read().unwrap();
+		let members = state.params(&channel).map(|p| p.participants.clone());
+		(
+			state.config().event_canister,
+			state.phase(&channel) == ChannelPhase::Withdrawn,
+			members.unwrap_or_default(),
+		)
+	};
+	let members = {
+		let mut events = events::STATE.write().unwrap();
+		events.add_members(&channel, &params_members);
+		if let Some(who) = event.participant() {
+			events.add_members(&channel, std::slice::from_ref(who));
+		}
+		events.members(&channel)
+	};
+	subscriptions::STATE.write().unwrap().publish(
+		blocktime(),
+		channel.clone(),
+		event.clone(),
+		&members,
+	);
+	match event_canister {
+		Some(canister) => {
+			if withdrawn {
+				// No further events of the channel follow.
+				events::STATE.write().unwrap().forget_members(&channel);
+			}
+			certify();
+			OUTBOX
+				.write()
+				.unwrap()
+				.push(blocktime(), channel, event, members);
+			RPCEventRegisterer::new(canister).flush().await
+		}
+		None => {
+			let mut events = events::STATE.write().unwrap();
+			events.push(blocktime(), channel.clone(), event);
+			if let Some(head) = events.head(&channel) {
+				STATE.write().unwrap().certify_event(&head);
 			}
-		},
-		_ => {
-			STATE.write().unwrap().deposit(funding, amount)?;
-			Err(Error::LedgerError)
+			drop(events);
+			certify();
 		}
 	}
 }
 
+/// Certifies the root hash of the canister's certified data tree. Has to be
+/// called after every change of a certified value.
+fn certify() {
+	ic_cdk::api::set_certified_data(&STATE.read().unwrap().certified_root());
+}
+
+/// Wraps a query response with the system's certificate and the given witness.
+fn certified<T>(value: T, witness: Witness) -> Certified<T> {
+	Certified {
+		value,
+		certificate: ByteBuf::from(ic_cdk::api::data_certificate().unwrap_or_default()),
+		witness,
+	}
+}
+
+#[ic_cdk_macros::query]
+/// Returns the locally stored events of a channel that were registered at or
+/// after the given time. Empty if events are forwarded to an event canister.
+fn query_events(ch: ChannelId, start: Timestamp) -> Vec<Event> {
+	events::STATE.read().unwrap().events_after(&ch, start)
+}
+
 #[ic_cdk_macros::query]
-/// Returns the funds deposited for a channel's specified participant, if any.
+/// Returns up to `limit` locally stored events of a channel, starting at the
+/// sequence number `cursor`, along with the cursor of the next page.
+fn query_events_page(ch: ChannelId, cursor: u64, limit: u64) -> EventPage {
+	events::STATE
+		.read()
+		.unwrap()
+		.events_page(&ch, cursor, limit)
+}
+
+#[ic_cdk_macros::query]
+/// Returns up to `limit` locally stored events of all channels, starting at the
+/// sequence number `cursor`, along with the cursor of the next page.
+fn query_all_events(cursor: u64, limit: u64) -> EventPage {
+	events::STATE.read().unwrap().all_events(cursor, limit)
+}
+
+#[ic_cdk_macros::query]
+/// Returns up to `limit` locally stored events of the channels in which a
+/// participant takes part, starting at the sequence number `cursor`, along with
+/// the cursor of the next page.
+fn query_participant_events(who: L2Account, cursor: u64, limit: u64) -> EventPage {
+	events::STATE
+		.read()
+		.unwrap()
+		.participant_events(&who, cursor, limit)
+}
+
+#[ic_cdk_macros::query]
+/// Like `query_events_page`, but also returns the channel's latest event along
+/// with a certificate and a witness for it. The latest event tells whether
+/// the channel has further events than the returned ones.
+fn query_events_certified(
+	ch: ChannelId,
+	cursor: u64,
+	limit: u64,
+) -> Certified<(EventPage, Option<EventRecord>)> {
+	let events = events::STATE.read().unwrap();
+	let value = (events.events_page(&ch, cursor, limit), events.head(&ch));
+	certified(
+		value,
+		STATE
+			.read()
+			.unwrap()
+			.witness(&certification::events_key(&ch)),
+	)
+}
+
+#[ic_cdk_macros::query]
+/// Returns how many events are stored locally and the oldest one's time.
+fn query_event_metrics() -> EventMetrics {
+	events::STATE.read().unwrap().metrics()
+}
+
+#[ic_cdk_macros::query]
+/// Returns the funds of an asset deposited for a channel's specified
+/// participant, if any.
 /// this function should be used to check whether all participants have
 /// deposited their owed funds into a channel to ensure it is fully funded.
 fn query_holdings(funding: Funding) -> Option<Amount> {
 	STATE.read().unwrap().query_holdings(funding)
 }
 
+#[ic_cdk_macros::query]
+/// Like `query_holdings`, but also returns a certificate and a witness for the
+/// holdings, see [certification::verify].
+fn query_holdings_certified(funding: Funding) -> Certified<Option<Amount>> {
+	let state = STATE.read().unwrap();
+	let witness = state.witness(&certification::holdings_key(&funding));
+	certified(state.query_holdings(funding), witness)
+}
+
+#[ic_cdk_macros::query]
+/// Returns the journal entry of a funding's latest ledger withdrawal, if any.
+/// This function should be used to check the outcome of a withdrawal whose
+/// response was lost.
+fn query_withdrawal(funding: Funding) -> Option<WithdrawalEntry> {
+	STATE.read().unwrap().query_withdrawal(&funding)
+}
+
+#[ic_cdk_macros::query]
+/// Returns the ledger account to which funds have to be transferred to deposit
+/// them for a channel participant. The deposit is credited by a subsequent call
+/// to `deposit`.
+fn query_deposit_address(funding: Funding) -> ledger::DepositAddress {
+	ledger::DepositAddress::new(ic_cdk::id(), &funding)
+}
+
 #[ic_cdk_macros::query]
 /// Returns the latest registered state for a given channel and its dispute
 /// timeout. This function should be used to check for registered disputes.
@@ -177,51 +753,343 @@ fn query_state(id: ChannelId) -> Option<RegisteredState> {
 	STATE.read().unwrap().state(&id)
 }
 
+#[ic_cdk_macros::query]
+/// Like `query_state`, but also returns a certificate and a witness for the
+/// state, see [certification::verify]. Watchers should use this to make sure
+/// that no registered dispute is hidden from them.
+fn query_state_certified(id: ChannelId) -> Certified<Option<RegisteredState>> {
+	let state = STATE.read().unwrap();
+	certified(
+		state.state(&id),
+		state.witness(&certification::state_key(&id)),
+	)
+}
+
+#[ic_cdk_macros::update]
+/// Announces a channel's parameters and fully signed initial state while the
+/// channel is being funded, so that its status reports its funds and it moves
+/// into the Funded phase once the state's outcome is deposited.
+fn register_funding(params: Params, state: FullySignedState) -> Option<Error> {
+	STATE.write().unwrap().register_funding(params, state).err()
+}
+
+#[ic_cdk_macros::query]
+/// Returns a channel's lifecycle phase, challenge timeout, funds and the
+/// participants' withdrawals. The funds of a channel that is being funded are
+/// reported once it was announced via `register_funding`.
+fn query_channel_status(id: ChannelId) -> ChannelStatus {
+	STATE.read().unwrap().channel_status(&id, blocktime())
+}
+
+#[ic_cdk_macros::query]
+/// Returns the canister's deployment configuration.
+fn query_config() -> Config {
+	STATE.read().unwrap().config().clone()
+}
+
+#[ic_cdk_macros::update]
+/// Replaces the canister's configuration, e.g., to support further assets or
+/// to change the event canister. Only the canister's administrators may do so.
+fn update_config(config: Config) -> Option<Error> {
+	STATE
+		.write()
+		.unwrap()
+		.update_config(&ic_cdk::caller(), config)
+		.err()
+}
+
 impl<Q> CanisterState<Q>
 where
 	Q: icp::TXQuerier,
 {
-	pub fn new(q: Q, my_principal: Principal) -> Self {
+	pub fn new(q: Q, my_principal: Principal, config: Config) -> Self {
 		Self {
+			config,
+			principal: my_principal,
 			icp_receiver: icp::Receiver::new(q, my_principal),
 			holdings: Default::default(),
 			channels: Default::default(),
+			parents: Default::default(),
+			params: Default::default(),
+			phases: Default::default(),
+			targets: Default::default(),
+			withdrawals: Default::default(),
+			sweeps: Default::default(),
+			nonces: Default::default(),
+			timeouts: Default::default(),
+			payouts: Default::default(),
+			certified: Default::default(),
 		}
 	}
+
+	/// Returns a copy of the state that needs to be preserved across canister
+	/// upgrades.
+	pub fn snapshot(&self) -> stable::CanisterSnapshot {
+		stable::CanisterSnapshot {
+			config: self.config.clone(),
+			receiver: self.icp_receiver.state(),
+			holdings: self.holdings.clone(),
+			channels: self.channels.clone(),
+			params: self.params.clone(),
+			phases: self.phases.clone(),
+			targets: self.targets.clone(),
+			withdrawals: self.withdrawals.clone(),
+			sweeps: self.sweeps.clone(),
+			nonces: self.nonces.clone(),
+			timeouts: self.timeouts.clone(),
+			payouts: self.payouts.clone(),
+		}
+	}
+
+	/// Replaces the canister's configuration, channels, holdings, withdrawals,
+	/// scheduled settlements and received transactions with a previously taken
+	/// snapshot.
+	pub fn restore(&mut self, snapshot: stable::CanisterSnapshot) {
+		self.config = snapshot.config;
+		self.icp_receiver.restore(snapshot.receiver);
+		self.holdings = snapshot.holdings;
+		self.channels = snapshot.channels;
+		self.params = snapshot.params;
+		self.phases = snapshot.phases;
+		self.targets = snapshot.targets;
+		self.withdrawals = snapshot.withdrawals;
+		self.sweeps = snapshot.sweeps;
+		self.nonces = snapshot.nonces;
+		self.timeouts = snapshot.timeouts;
+		self.payouts = snapshot.payouts;
+
+		self.certified = Default::default();
+		for (funding, amount) in self.holdings.iter() {
+			self.certified
+				.set(certification::holdings_key(funding), Some(amount));
+		}
+		self.parents.clear();
+		for (id, state) in self.channels.iter() {
+			self.certified
+				.set(certification::state_key(id), Some(state));
+			for sub in state.state.locked.iter() {
+				self.parents.insert(sub.id.clone(), id.clone());
+			}
+		}
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+
+	/// Replaces the canister's configuration on behalf of one of its
+	/// administrators.
+	pub fn update_config(&mut self, caller: &L1Account, config: Config) -> Result<()> {
+		require!(self.config.is_admin(caller), Authentication);
+		self.config = config;
+		Ok(())
+	}
+
+	/// The canister's own principal, which all signatures are bound to.
+	pub fn principal(&self) -> &Principal {
+		&self.principal
+	}
+
 	pub fn deposit(&mut self, funding: Funding, amount: Amount) -> Result<()> {
-		*self.holdings.entry(funding).or_insert(Default::default()) += amount;
+		*self
+			.holdings
+			.entry(funding.clone())
+			.or_insert(Default::default()) += amount;
+		self.certify_holdings(&funding);
+		self.phases
+			.entry(funding.channel.clone())
+			.or_insert(ChannelPhase::Funding);
+		self.mark_funded(&funding.channel);
 		Ok(())
 	}
 
-	/// Call this to access funds deposited and previously registered.
-	pub async fn deposit_icp(&mut self, time: Timestamp, funding: Funding) -> Result<()> {
-		let memo = funding.memo();
-		let amount = self.icp_receiver.drain(memo);
-		self.deposit(funding.clone(), amount)?;
-		events::STATE
-			.write()
-			.unwrap()
-			.register_event(
-				time,
-				funding.channel.clone(),
-				Event::Funded {
-					who: funding.participant.clone(),
-					total: self.holdings.get(&funding).cloned().unwrap(),
-				},
-			)
-			.await;
+	/// Announces the parameters and fully signed initial state of a channel
+	/// that is being funded. The channel moves into the Funded phase as soon
+	/// as its holdings cover the state's outcome.
+	pub fn register_funding(&mut self, params: Params, state: FullySignedState) -> Result<()> {
+		state.validate(&params, &self.principal)?;
+		require!(state.state.version == 0, InvalidInput);
+		require!(
+			params.assets.iter().all(|a| self.config.supports(a)),
+			UnsupportedAsset
+		);
+		let channel = params.id();
+		// Only channels that are still being funded can be announced.
+		self.phase(&channel).transition(ChannelPhase::Funded)?;
+		self.targets.insert(channel.clone(), state.state.total());
+		self.params.insert(channel.clone(), params);
+		self.mark_funded(&channel);
 		Ok(())
 	}
 
-	/// Call this to process an ICP transaction and register the funds for
-	/// further use.
-	pub async fn process_icp_tx(&mut self, tx: icp::BlockHeight) -> Result<Amount> {
-		match self.icp_receiver.verify(tx).await {
-			Ok(v) => Ok(v),
-			Err(e) => Err(Error::ReceiverError(e)),
+	/// Call this to credit the funds swept from a funding's deposit address.
+	/// Only in the legacy memo deposit mode, i.e., with the `memo-deposits`
+	/// feature, funds registered via transaction notifications with the
+	/// funding's memo are credited as well. Memos are truncated hashes, so that
+	/// such funds may be claimed by another funding with the same memo. Returns
+	/// the event to register for the deposit, or nothing if there were no funds
+	/// to credit, in which case the state is left untouched.
+	pub fn deposit_icp(&mut self, funding: Funding, swept: Amount) -> Result<Option<Event>> {
+		let mut amount = swept;
+		if cfg!(feature = "memo-deposits") {
+			amount += self.icp_receiver.drain(&funding.asset, funding.memo());
+		}
+		if amount == Amount::default() {
+			return Ok(None);
+		}
+		self.deposit_received(funding, amount).map(Some)
+	}
+
+	/// Returns the sweep of a funding's deposit address whose outcome is not
+	/// known yet, if any.
+	pub fn pending_sweep(&self, funding: &Funding) -> Option<SweepEntry> {
+		self.sweeps.get(funding).cloned()
+	}
+
+	/// Records a sweep of a funding's deposit address in the sweep journal
+	/// before its ledger transfer is executed. If another sweep of the funding
+	/// is already journaled, returns that one instead, so that its transfer is
+	/// repeated with identical arguments.
+	pub fn begin_sweep(&mut self, funding: &Funding, sweep: SweepEntry) -> SweepEntry {
+		self.sweeps.entry(funding.clone()).or_insert(sweep).clone()
+	}
+
+	/// Records the outcome of a journaled sweep's ledger transfer. Executed
+	/// sweeps, including those the ledger reports as duplicates, are credited
+	/// to the funding along with its registered deposits, unless a concurrent
+	/// call already credited them. Sweeps with unknown outcome stay journaled,
+	/// including sweeps that left the ledger's deduplication window, as the
+	/// ledger cannot tell whether they were executed before. Those are settled
+	/// via [Self::settle_sweep]. Rejected sweeps are dropped, so that
+	/// the next deposit sweeps the deposit address anew. Returns the event to
+	/// register for the deposit, if any funds were credited.
+	pub fn finish_sweep(
+		&mut self,
+		funding: &Funding,
+		sweep: &SweepEntry,
+		result: core::result::Result<icp::BlockHeight, icp::PayoutError>,
+	) -> Result<Option<Event>> {
+		let journaled = self.sweeps.get(funding) == Some(sweep);
+		match result {
+			Ok(block) => {
+				// The sweep cannot be claimed via a transaction notification.
+				self.icp_receiver.claim(&funding.asset, block);
+				let mut swept = Amount::default();
+				if journaled {
+					self.sweeps.remove(funding);
+					swept = sweep.amount.clone();
+				}
+				self.deposit_icp(funding.clone(), swept)
+			}
+			Err(icp::PayoutError::Unknown | icp::PayoutError::Expired) => Err(Error::LedgerError),
+			Err(icp::PayoutError::Rejected) => {
+				if journaled {
+					self.sweeps.remove(funding);
+				}
+				Err(Error::LedgerError)
+			}
 		}
 	}
 
+	/// Settles a journaled sweep on behalf of one of the canister's
+	/// administrators, who looked up on the ledger whether its transfer was
+	/// executed. Credits the sweep if it was executed in the given block, and
+	/// drops it if there is none, so that the next deposit sweeps the deposit
+	/// address anew. Returns the event to register for the deposit, if any.
+	pub fn settle_sweep(
+		&mut self,
+		caller: &L1Account,
+		funding: &Funding,
+		block: Option<icp::BlockHeight>,
+	) -> Result<Option<Event>> {
+		require!(self.config.is_admin(caller), Authentication);
+		let sweep = self.pending_sweep(funding).ok_or(Error::InvalidInput)?;
+		match block {
+			Some(block) => self.finish_sweep(funding, &sweep, Ok(block)),
+			None => {
+				self.sweeps.remove(funding);
+				Ok(None)
+			}
+		}
+	}
+
+	/// Credits funds that the canister already received to a funding. Returns
+	/// the event to register for the deposit.
+	pub fn deposit_received(&mut self, funding: Funding, amount: Amount) -> Result<Event> {
+		self.deposit(funding.clone(), amount)?;
+		Ok(Event::Funded {
+			total: self.holdings.get(&funding).cloned().unwrap(),
+			who: funding.participant,
+			asset: funding.asset,
+		})
+	}
+
+	/// Credits funds that were pulled from an allowance in the given ledger
+	/// block to a funding, unless the block was already credited. The block is
+	/// marked as processed, so that its funds cannot be claimed again via a
+	/// transaction notification. Returns the event to register, or nothing if
+	/// the block was already credited.
+	pub fn deposit_pulled(
+		&mut self,
+		funding: Funding,
+		amount: Amount,
+		block: icp::BlockHeight,
+	) -> Result<Option<Event>> {
+		if !self.icp_receiver.claim(&funding.asset, block) {
+			return Ok(None);
+		}
+		self.deposit_received(funding, amount).map(Some)
+	}
+
+	/// Call this to process a transaction on a supported asset's ledger and
+	/// register the funds for further use.
+	pub async fn process_icp_tx(&mut self, asset: Asset, tx: icp::BlockHeight) -> Result<Amount> {
+		self.check_icp_tx(&asset, tx)?;
+		let notification = self
+			.icp_receiver
+			.query(&asset, tx)
+			.await
+			.map_err(Error::ReceiverError)?;
+		self.record_icp_tx(&asset, tx, notification)
+	}
+
+	/// Checks that a transaction on a supported asset's ledger was not
+	/// processed yet. Has to be called before querying the transaction for
+	/// [CanisterState::record_icp_tx].
+	pub fn check_icp_tx(&self, asset: &Asset, tx: icp::BlockHeight) -> Result<()> {
+		require!(self.config.supports(asset), UnsupportedAsset);
+		self.icp_receiver
+			.check(asset, tx)
+			.map_err(Error::ReceiverError)
+	}
+
+	/// Registers the funds of a transaction that was queried from the asset's
+	/// ledger, unless it was processed meanwhile, and returns its amount. The
+	/// canister's own sweeps are recognised by their source account, a
+	/// journaled sweep's deposit address, and are marked as processed without
+	/// registering their funds, which the sweep credits. Executed sweeps are
+	/// marked as processed when their outcome is recorded.
+	pub fn record_icp_tx(
+		&mut self,
+		asset: &Asset,
+		tx: icp::BlockHeight,
+		notification: icp::TransactionNotification,
+	) -> Result<Amount> {
+		if let Some(from) = &notification.from {
+			let sweep = self.sweeps.keys().any(|f| {
+				&f.asset == asset
+					&& &ledger::DepositAddress::new(self.principal, f).account_identifier == from
+			});
+			if sweep {
+				self.icp_receiver.claim(asset, tx);
+				return Err(Error::ReceiverError(icp::ICPReceiverError::TransactionType));
+			}
+		}
+		self.icp_receiver
+			.record(asset, tx, notification)
+			.map_err(Error::ReceiverError)
+	}
+
 	pub fn query_holdings(&self, funding: Funding) -> Option<Amount> {
 		self.holdings.get(&funding).cloned()
 	}
@@ -231,60 +1099,419 @@ where
 		self.channels.get(&id).cloned()
 	}
 
+	/// Returns the parameters of a channel that was registered or announced
+	/// for funding.
+	pub fn params(&self, id: &ChannelId) -> Option<&Params> {
+		self.params.get(id)
+	}
+
+	/// Returns a channel's lifecycle phase.
+	pub fn phase(&self, id: &ChannelId) -> ChannelPhase {
+		self.phases
+			.get(id)
+			.copied()
+			.unwrap_or(ChannelPhase::Funding)
+	}
+
+	/// Returns whether a channel's registered state is settled. A disputed app
+	/// channel only settles a challenge duration after its dispute timed out,
+	/// so that its participants can advance it via [Self::progress].
+	fn settled(&self, state: &RegisteredState, now: Timestamp) -> bool {
+		let channel = &state.state.channel;
+		state.settled(now.saturating_sub(self.force_exec_duration(channel)))
+	}
+
+	/// Returns how long a disputed app channel can still be advanced after
+	/// its dispute timed out, which is zero for all other channels.
+	fn force_exec_duration(&self, channel: &ChannelId) -> Duration {
+		let disputed = self.phase(channel) == ChannelPhase::Disputed;
+		match self.params.get(channel) {
+			Some(params) if disputed && params.app.is_some() => params.challenge_duration,
+			_ => 0,
+		}
+	}
+
+	/// Returns a channel's lifecycle phase along with its funds and the
+	/// participants' withdrawals. Disputed channels are reported as concluded
+	/// once settled, even before their scheduled settlement. The funds of
+	/// channels that are being funded are only known once they were announced.
+	pub fn channel_status(&self, id: &ChannelId, now: Timestamp) -> ChannelStatus {
+		let mut phase = self.phase(id);
+		let registered = self.state(id);
+		let settled = registered.as_ref().map_or(false, |s| self.settled(s, now));
+		if settled && matches!(phase, ChannelPhase::Disputed | ChannelPhase::ForceExec) {
+			phase = ChannelPhase::Concluded;
+		}
+		let mut status = ChannelStatus {
+			phase,
+			timeout: registered.filter(|s| !s.state.finalized).map(|s| s.timeout),
+			funded: vec![],
+			participants: vec![],
+		};
+		let params = match self.params.get(id) {
+			Some(params) => params,
+			None => return status,
+		};
+
+		status.funded = self.holdings_total(params);
+		let concluded = matches!(phase, ChannelPhase::Concluded | ChannelPhase::Withdrawn);
+		for part in params.participants.iter() {
+			let fundings: Vec<_> = params
+				.assets
+				.iter()
+				.map(|asset| Funding::new(id.clone(), part.clone(), asset.clone()))
+				.collect();
+			let holdings: Vec<_> = fundings
+				.iter()
+				.map(|f| self.query_holdings(f.clone()).unwrap_or_default())
+				.collect();
+			let withdrawals: Vec<_> = fundings.iter().map(|f| self.query_withdrawal(f)).collect();
+			// Funds whose ledger transfer is pending are not withdrawn yet.
+			let pending = withdrawals
+				.iter()
+				.flatten()
+				.any(|e| e.status == WithdrawalStatus::Pending);
+			let empty = holdings.iter().all(|h| *h == Amount::default());
+			status.participants.push(ParticipantStatus {
+				participant: part.clone(),
+				withdrawn: concluded && empty && !pending,
+				holdings,
+				withdrawals,
+			});
+		}
+		status
+	}
+
 	/// Updates the holdings associated with a channel to the outcome of the
-	/// supplied state, then registers the state. If the state is the channel's
-	/// initial state, the holdings are not updated, as initial states are
-	/// allowed to be under-funded and are otherwise expected to match the
-	/// deposit distribution exactly if fully funded.
-	fn register_channel(&mut self, params: &Params, state: RegisteredState) -> Result<()> {
-		let total = &self.holdings_total(&params);
-		if total < &state.state.total() {
-			require!(state.state.may_be_underfunded(), InsufficientFunding);
-		} else {
-			self.update_holdings(&params, &state.state);
+	/// supplied state, then registers the state and moves the channel into the
+	/// given phase. If the state is the channel's initial state, the holdings
+	/// are not updated, as initial states are allowed to be under-funded and
+	/// are otherwise expected to match the deposit distribution exactly if
+	/// fully funded.
+	fn register_channel(
+		&mut self,
+		params: &Params,
+		state: RegisteredState,
+		phase: ChannelPhase,
+	) -> Result<()> {
+		require!(
+			params.assets.iter().all(|a| self.config.supports(a)),
+			UnsupportedAsset
+		);
+		match app::channel_app(params)? {
+			// All participants signed a final state, so the app's rules do not
+			// constrain it, e.g., to close a channel right after funding it.
+			Some(_) if state.state.finalized => {}
+			Some(app) if state.state.version == 0 => app.valid_init(params, &state.state)?,
+			Some(_) => {}
+			None => require!(state.state.data.is_empty(), InvalidInput),
 		}
+		let channel = state.state.channel.clone();
+		let outcome = state.state.total();
+		// Sub-channels are funded by the funds their parent channel locks for
+		// them, not by deposits.
+		let parent_lock = self.parent_lock(&channel);
+		let funded = match &parent_lock {
+			Some(locked) => &outcome == locked,
+			None => {
+				let total = self.holdings_total(&params);
+				total.iter().zip(outcome.iter()).all(|(t, o)| t >= o)
+			}
+		};
+		// Underfunded states must not lock funds in sub-channels, as the locked
+		// funds would be released from the canister's holdings without having
+		// been deposited.
+		require!(
+			funded || (state.state.may_be_underfunded() && state.state.locked.is_empty()),
+			InsufficientFunding
+		);
+		let mut current = self.phase(&channel);
+		if funded && current == ChannelPhase::Funding {
+			current = current.transition(ChannelPhase::Funded)?;
+		}
+		let phase = current.transition(phase)?;
 
-		self.channels.insert(state.state.channel.clone(), state);
+		if funded && parent_lock.is_none() {
+			self.update_holdings(&params, &state.state);
+		}
+		self.insert_channel(state);
+		self.params.insert(channel.clone(), params.clone());
+		self.phases.insert(channel.clone(), phase);
+		self.targets.remove(&channel);
+		self.certify_state(&channel);
 		Ok(())
 	}
 
+	/// Stores a channel's registered state and indexes the sub-channels it
+	/// locks funds for.
+	fn insert_channel(&mut self, state: RegisteredState) {
+		let channel = state.state.channel.clone();
+		if let Some(old) = self.channels.get(&channel) {
+			for sub in old.state.locked.iter() {
+				if self.parents.get(&sub.id) == Some(&channel) {
+					self.parents.remove(&sub.id);
+				}
+			}
+		}
+		for sub in state.state.locked.iter() {
+			self.parents.insert(sub.id.clone(), channel.clone());
+		}
+		self.channels.insert(channel, state);
+	}
+
+	/// Returns the funds that a registered channel locks for a sub-channel, if
+	/// any.
+	fn parent_lock(&self, channel: &ChannelId) -> Option<Vec<Amount>> {
+		let parent = self.parents.get(channel)?;
+		self.channels[parent]
+			.state
+			.locked
+			.iter()
+			.find(|sub| &sub.id == channel)
+			.map(|sub| sub.balances.clone())
+	}
+
+	/// Moves an announced channel into the Funded phase once its holdings
+	/// cover the outcome of its initial state.
+	fn mark_funded(&mut self, channel: &ChannelId) {
+		let funded = match (self.params.get(channel), self.targets.get(channel)) {
+			(Some(params), Some(target)) => self
+				.holdings_total(params)
+				.iter()
+				.zip(target.iter())
+				.all(|(h, t)| h >= t),
+			_ => false,
+		};
+		if funded {
+			if let Ok(phase) = self.phase(channel).transition(ChannelPhase::Funded) {
+				self.phases.insert(channel.clone(), phase);
+			}
+		}
+	}
+
+	/// Moves a settled channel into the Concluded phase, unless it already is
+	/// concluded.
+	fn mark_concluded(&mut self, channel: &ChannelId) {
+		if let Ok(phase) = self.phase(channel).transition(ChannelPhase::Concluded) {
+			self.phases.insert(channel.clone(), phase);
+		}
+	}
+
+	/// Moves a settled channel into the Withdrawn phase once all its funds were
+	/// withdrawn, none are locked in sub-channels anymore and no withdrawal's
+	/// ledger transfer is pending.
+	fn mark_withdrawn(&mut self, channel: &ChannelId) {
+		let empty = match (self.params.get(channel), self.channels.get(channel)) {
+			(Some(params), Some(state)) => {
+				state.state.locked.is_empty()
+					&& self
+						.holdings_total(params)
+						.iter()
+						.all(|t| *t == Amount::default())
+					&& !self.withdrawal_pending(params, channel)
+			}
+			_ => false,
+		};
+		if empty {
+			self.mark_concluded(channel);
+			if let Ok(phase) = self.phase(channel).transition(ChannelPhase::Withdrawn) {
+				self.phases.insert(channel.clone(), phase);
+			}
+		}
+	}
+
+	/// Returns whether a ledger withdrawal of a channel's funds is pending.
+	fn withdrawal_pending(&self, params: &Params, channel: &ChannelId) -> bool {
+		params
+			.participants
+			.iter()
+			.flat_map(|p| {
+				params
+					.assets
+					.iter()
+					.map(move |a| Funding::new(channel.clone(), p.clone(), a.clone()))
+			})
+			.any(|f| {
+				self.withdrawals
+					.get(&f)
+					.map_or(false, |e| e.status == WithdrawalStatus::Pending)
+			})
+	}
+
 	/// Pushes a state's funding allocation into the channel's holdings mapping
 	/// in the canister.
 	fn update_holdings(&mut self, params: &Params, state: &State) {
-		for (i, outcome) in state.allocation.iter().enumerate() {
-			self.holdings.insert(
-				Funding::new(state.channel.clone(), params.participants[i].clone()),
-				outcome.clone(),
-			);
+		for (a, balances) in state.allocation.iter().enumerate() {
+			for (i, outcome) in balances.iter().enumerate() {
+				let funding = Funding::new(
+					state.channel.clone(),
+					params.participants[i].clone(),
+					params.assets[a].clone(),
+				);
+				self.holdings.insert(funding.clone(), outcome.clone());
+				self.certify_holdings(&funding);
+			}
 		}
 	}
 
-	/// Calculates the total funds held in a channel. If the channel is unknown
-	/// and there are no deposited funds for the channel, returns 0.
-	pub fn holdings_total(&self, params: &Params) -> Amount {
-		let mut acc = Amount::default();
-		for pk in params.participants.iter() {
-			let funding = Funding::new(params.id(), pk.clone());
-			acc += self
-				.holdings
-				.get(&funding)
-				.unwrap_or(&Amount::default())
-				.clone();
-		}
-		acc
+	/// Calculates the total funds of each asset held in a channel, in the order
+	/// of the parameters' asset list. If the channel is unknown and there are
+	/// no deposited funds for the channel, returns 0 for each asset.
+	pub fn holdings_total(&self, params: &Params) -> Vec<Amount> {
+		let id = params.id();
+		params
+			.assets
+			.iter()
+			.map(|asset| {
+				let mut acc = Amount::default();
+				for pk in params.participants.iter() {
+					let funding = Funding::new(id.clone(), pk.clone(), asset.clone());
+					acc += self
+						.holdings
+						.get(&funding)
+						.unwrap_or(&Amount::default())
+						.clone();
+				}
+				acc
+			})
+			.collect()
 	}
 
+	/// Concludes a channel with a final state. Final states that lock funds in
+	/// sub-channels have to be concluded via
+	/// [Self::conclude_with_subchannels].
 	pub fn conclude(
 		&mut self,
 		params: Params,
 		state: FullySignedState,
 		now: Timestamp,
 	) -> Result<()> {
-		if let Some(old_state) = self.state(&state.state.channel) {
-			require!(!old_state.settled(now), AlreadyConcluded);
+		self.conclude_with_subchannels(params, state, vec![], now)
+	}
+
+	/// Concludes a channel and distributes the funds it locks in sub-channels
+	/// according to the sub-channels' final states, which in turn may lock
+	/// funds in further sub-channels. Instead of supplying its final state, a
+	/// sub-channel can also be disputed against its parent's locked funds, in
+	/// which case its registered state is used once its dispute timed out. The
+	/// released funds are credited to the channel's holdings of the
+	/// sub-channels' participants, which have to be participants of the
+	/// channel, and the registered state's sub-allocations are merged into its
+	/// allocation. The channel's state has to be final, or
+	/// the registered state of a channel whose dispute timed out with funds
+	/// still locked.
+	pub fn conclude_with_subchannels(
+		&mut self,
+		params: Params,
+		state: FullySignedState,
+		subchannels: Vec<SubChannel>,
+		now: Timestamp,
+	) -> Result<()> {
+		let channel = state.state.channel.clone();
+		match self.state(&channel) {
+			Some(mut old_state) if self.settled(&old_state, now) => {
+				require!(!old_state.state.locked.is_empty(), AlreadyConcluded);
+				require!(old_state.state == state.state, OutdatedState);
+				require!(channel == params.id(), InvalidInput);
+
+				let released = self.release_locked(&params, &old_state.state, &subchannels, now)?;
+				for (a, balances) in released.iter().enumerate() {
+					for (i, amount) in balances.iter().enumerate() {
+						let funding = Funding::new(
+							channel.clone(),
+							params.participants[i].clone(),
+							params.assets[a].clone(),
+						);
+						self.deposit(funding, amount.clone())?;
+					}
+				}
+				old_state.state.merge_released(released);
+				self.insert_channel(old_state);
+				self.certify_state(&channel);
+				self.mark_concluded(&channel);
+				Ok(())
+			}
+			_ => {
+				let mut state = RegisteredState::conclude(state, &params, &self.principal)?;
+				let released = self.release_locked(&params, &state.state, &subchannels, now)?;
+				state.state.merge_released(released);
+				self.register_channel(&params, state, ChannelPhase::Concluded)?;
+				self.schedule_settlement(&channel, now);
+				Ok(())
+			}
 		}
+	}
+
+	/// Calculates the funds that a state locks in sub-channels, for each asset
+	/// and participant of the channel with the parameters `root`. Each
+	/// sub-channel's outcome is taken from its supplied final state or, if none
+	/// is supplied, from its registered state once that is settled, e.g.,
+	/// because its dispute timed out. The outcome of each sub-channel
+	/// participant is credited to the `root` participant that the index maps of
+	/// the sub-allocations lead to, so that virtual channels can have
+	/// participants that are not part of `root`. Each sub-channel can be
+	/// released only once, which also rules out cycles.
+	fn release_locked(
+		&self,
+		root: &Params,
+		state: &State,
+		subchannels: &[SubChannel],
+		now: Timestamp,
+	) -> Result<Vec<Vec<Amount>>> {
+		let mut released =
+			vec![vec![Amount::default(); root.participants.len()]; root.assets.len()];
+		let mut seen = vec![];
+		// The sub-allocations to release, along with the index of the `root`
+		// participant receiving the outcome of each participant of the channel
+		// that locks them.
+		let identity: Vec<usize> = (0..root.participants.len()).collect();
+		let mut pending: Vec<_> = state
+			.locked
+			.iter()
+			.map(|sub| (sub.clone(), identity.clone()))
+			.collect();
+		while let Some((sub, map)) = pending.pop() {
+			require!(!seen.contains(&sub.id), InvalidInput);
+			seen.push(sub.id.clone());
+			let (params, state) = match subchannels.iter().find(|c| c.params.id() == sub.id) {
+				Some(SubChannel { params, state }) => {
+					state.validate_final(params, &self.principal)?;
+					(params.clone(), state.state.clone())
+				}
+				None => {
+					let registered = self
+						.state(&sub.id)
+						.filter(|s| self.settled(s, now))
+						.ok_or(Error::SubChannelNotConcluded)?;
+					let params = self.params[&sub.id].clone();
+					(params, registered.state)
+				}
+			};
+			require!(params.assets == root.assets, InvalidInput);
+			require!(state.total() == sub.balances, InvalidInput);
+			require!(
+				sub.index_map.len() == params.participants.len(),
+				InvalidInput
+			);
+			let sub_map = sub
+				.index_map
+				.iter()
+				.map(|&k| map.get(k as usize).copied().ok_or(Error::InvalidInput))
+				.collect::<Result<Vec<_>>>()?;
 
-		self.register_channel(&params, RegisteredState::conclude(state, &params)?)
+			for (a, balances) in state.allocation.iter().enumerate() {
+				for (j, amount) in balances.iter().enumerate() {
+					released[a][sub_map[j]] += amount.clone();
+				}
+			}
+			pending.extend(
+				state
+					.locked
+					.iter()
+					.map(|locked| (locked.clone(), sub_map.clone())),
+			);
+		}
+		Ok(released)
 	}
 
 	pub fn dispute(
@@ -298,7 +1525,165 @@ where
 			require!(old_state.state.version < state.state.version, OutdatedState);
 		}
 
-		self.register_channel(&params, RegisteredState::dispute(state, &params, now)?)
+		let state = RegisteredState::dispute(state, &params, now, &self.principal)?;
+		let channel = state.state.channel.clone();
+		let (finalized, timeout) = (state.state.finalized, state.timeout);
+		self.register_channel(&params, state, ChannelPhase::Disputed)?;
+		let settlement = if finalized {
+			now
+		} else {
+			timeout + self.force_exec_duration(&channel)
+		};
+		self.schedule_settlement(&channel, settlement);
+		Ok(())
+	}
+
+	/// Advances a disputed app channel's registered state `old` to `new`, which
+	/// only the participant whose turn it is signed, if the channel's app
+	/// allows the transition. A disputed channel can only be advanced after its
+	/// challenge duration elapsed, and then until the restarted challenge
+	/// duration elapses without a further transition. Settles the channel
+	/// right away if the new state is final. Mirrors go-perun's
+	/// force-execution.
+	pub fn progress(
+		&mut self,
+		params: Params,
+		old: State,
+		new: State,
+		sig: L2Signature,
+		now: Timestamp,
+	) -> Result<()> {
+		let app = app::channel_app(&params)?.ok_or(Error::InvalidInput)?;
+		require!(new.channel == params.id(), InvalidInput);
+		let registered = self.state(&new.channel).ok_or(Error::InvalidInput)?;
+		if self.phase(&new.channel) == ChannelPhase::Disputed {
+			require!(registered.timeout <= now, ChallengePending);
+		}
+		require!(!self.settled(&registered, now), AlreadyConcluded);
+		require!(registered.state == old, OutdatedState);
+		app::valid_transition_generic(&old, &new)?;
+
+		let actor = params
+			.participants
+			.iter()
+			.position(|pk| new.validate_sig(&sig, pk, &self.principal).is_ok())
+			.ok_or(Error::Authentication)?;
+		app.valid_transition(&params, &old, &new, actor)?;
+
+		let channel = new.channel.clone();
+		let state = RegisteredState {
+			timeout: now + params.challenge_duration,
+			state: new,
+		};
+		let settlement = if state.state.finalized {
+			now
+		} else {
+			state.timeout
+		};
+		self.register_channel(&params, state, ChannelPhase::ForceExec)?;
+		self.schedule_settlement(&channel, settlement);
+		Ok(())
+	}
+
+	/// Schedules a channel's settlement at the given time, replacing any
+	/// previously scheduled settlement. Finalized channels are settled right
+	/// away, disputed channels once their dispute times out.
+	fn schedule_settlement(&mut self, channel: &ChannelId, time: Timestamp) {
+		self.timeouts.retain(|(_, ch)| ch != channel);
+		self.timeouts.insert((time, channel.clone()));
+	}
+
+	/// Registers a withdrawal to be paid out once the channel is settled.
+	/// Replaces any withdrawal previously registered for the funding. Only
+	/// participants of announced or registered channels can register payouts.
+	pub fn register_payout(
+		&mut self,
+		req: WithdrawalRequest,
+		auth: L2Signature,
+		now: Timestamp,
+	) -> Result<()> {
+		req.validate_sig(&auth, &self.principal)?;
+		let params = self
+			.params
+			.get(&req.funding.channel)
+			.ok_or(Error::InvalidInput)?;
+		require!(
+			params.participants.contains(&req.funding.participant),
+			Authentication
+		);
+		self.check_replay(&req, now)?;
+		require!(self.config.supports(&req.funding.asset), UnsupportedAsset);
+		if let Some(state) = self.state(&req.funding.channel) {
+			require!(!self.settled(&state, now), AlreadyConcluded);
+		}
+
+		let payouts = self.payouts.entry(req.funding.channel.clone()).or_default();
+		payouts.retain(|p| p.funding != req.funding);
+		payouts.push(req);
+		Ok(())
+	}
+
+	/// Removes all scheduled settlements that are due. Returns the channels
+	/// that are now concluded and starts the withdrawals registered for them.
+	/// Payouts that cannot be started, e.g., because they do not exceed the
+	/// fee, are dropped and have to be withdrawn manually.
+	pub fn settle_expired(
+		&mut self,
+		now: Timestamp,
+	) -> (Vec<ChannelId>, Vec<(Funding, WithdrawalEntry)>) {
+		let mut concluded = vec![];
+		let mut withdrawals = vec![];
+		while let Some((timeout, channel)) = self.timeouts.iter().next().cloned() {
+			if timeout > now {
+				break;
+			}
+			self.timeouts.remove(&(timeout, channel.clone()));
+			self.mark_concluded(&channel);
+			withdrawals.extend(self.start_payouts(&channel, now));
+			concluded.push(channel);
+		}
+		(concluded, withdrawals)
+	}
+
+	/// Concludes a disputed channel whose challenge duration passed, without
+	/// waiting for its scheduled settlement, and starts the withdrawals
+	/// registered for it. The channel's holdings already match the registered
+	/// state, as they are updated whenever a state is registered. Fails if the
+	/// channel was already concluded, either explicitly or by its scheduled
+	/// settlement.
+	pub fn conclude_dispute(
+		&mut self,
+		channel: &ChannelId,
+		now: Timestamp,
+	) -> Result<Vec<(Funding, WithdrawalEntry)>> {
+		let state = self.state(channel).ok_or(Error::NotFinalized)?;
+		let scheduled = self
+			.timeouts
+			.iter()
+			.find(|(_, ch)| ch == channel)
+			.cloned()
+			.ok_or(Error::AlreadyConcluded)?;
+		require!(self.settled(&state, now), NotFinalized);
+
+		self.timeouts.remove(&scheduled);
+		self.mark_concluded(channel);
+		Ok(self.start_payouts(channel, now))
+	}
+
+	/// Starts the withdrawals registered for a settled channel.
+	fn start_payouts(
+		&mut self,
+		channel: &ChannelId,
+		now: Timestamp,
+	) -> Vec<(Funding, WithdrawalEntry)> {
+		let mut withdrawals = vec![];
+		for req in self.payouts.remove(channel).unwrap_or_default() {
+			let funding = req.funding.clone();
+			if let Ok(entry) = self.start_withdrawal(req, now) {
+				withdrawals.push((funding, entry));
+			}
+		}
+		withdrawals
 	}
 
 	pub fn withdraw(
@@ -307,12 +1692,229 @@ where
 		auth: L2Signature,
 		now: Timestamp,
 	) -> Result<Amount> {
-		req.validate_sig(&auth)?;
-		match self.state(&req.funding.channel) {
+		self.authorize_withdrawal(&req, &auth, now)?;
+		self.check_replay(&req, now)?;
+		let amount = self.holdings.remove(&req.funding).unwrap_or_default();
+		self.certify_holdings(&req.funding);
+		self.mark_withdrawn(&req.funding.channel);
+		self.nonces.insert(req.funding, req.nonce);
+		Ok(amount)
+	}
+
+	/// Removes a funding's holdings for a withdrawal via the ledger. The
+	/// ledger's transfer fee is deducted from the withdrawn funds. Withdrawals
+	/// that do not exceed the fee are rejected and leave the holdings
+	/// untouched. Returns the amount to transfer and the fee.
+	fn deduct_withdrawal(&mut self, funding: &Funding) -> Result<(Amount, Amount)> {
+		let fee = self
+			.config
+			.fee(&funding.asset)
+			.ok_or(Error::UnsupportedAsset)?;
+		let holding = self.query_holdings(funding.clone()).unwrap_or_default();
+		require!(holding > fee, WithdrawalBelowFee);
+
+		self.holdings.remove(funding);
+		self.certify_holdings(funding);
+		Ok((holding - fee.clone(), fee))
+	}
+
+	/// Starts a ledger withdrawal and records it as pending in the withdrawal
+	/// journal. If the funding's previous withdrawal is still pending, returns
+	/// that withdrawal instead, so that its transfer is repeated with identical
+	/// arguments, including the original receiver. Otherwise, the request
+	/// must neither be expired nor replayed.
+	pub fn begin_withdrawal(
+		&mut self,
+		req: WithdrawalRequest,
+		auth: L2Signature,
+		now: Timestamp,
+	) -> Result<WithdrawalEntry> {
+		self.authorize_withdrawal(&req, &auth, now)?;
+		self.start_withdrawal(req, now)
+	}
+
+	/// Starts a ledger withdrawal to the caller, which has to be the principal
+	/// that the channel's parameters bind to the funds' owner, see
+	/// [Self::begin_withdrawal].
+	pub fn begin_withdrawal_as(
+		&mut self,
+		funding: Funding,
+		caller: Principal,
+		now: Timestamp,
+	) -> Result<WithdrawalEntry> {
+		require!(caller != Principal::anonymous(), Authentication);
+		let principal = self
+			.params
+			.get(&funding.channel)
+			.and_then(|params| params.principal(&funding.participant));
+		require!(principal == Some(caller), Authentication);
+		self.require_settled(&funding.channel, now)?;
+
+		let nonce = match self.nonces.get(&funding) {
+			Some(n) => n.checked_add(1).ok_or(Error::WithdrawalNonceUsed)?,
+			None => 0,
+		};
+		self.start_withdrawal(WithdrawalRequest::new(funding, caller, nonce), now)
+	}
+
+	/// Starts an already authorized ledger withdrawal, see
+	/// [Self::begin_withdrawal].
+	fn start_withdrawal(
+		&mut self,
+		req: WithdrawalRequest,
+		now: Timestamp,
+	) -> Result<WithdrawalEntry> {
+		if let Some(entry) = self.withdrawals.get(&req.funding) {
+			if entry.status == WithdrawalStatus::Pending {
+				return Ok(entry.clone());
+			}
+		}
+
+		self.check_replay(&req, now)?;
+		let (amount, fee) = self.deduct_withdrawal(&req.funding)?;
+		self.nonces.insert(req.funding.clone(), req.nonce);
+		let entry = WithdrawalEntry {
+			receiver: req.receiver.clone(),
+			amount,
+			fee,
+			created_at_time: now,
+			status: WithdrawalStatus::Pending,
+		};
+		self.withdrawals.insert(req.funding, entry.clone());
+		Ok(entry)
+	}
+
+	/// Records the outcome of a pending withdrawal's ledger transfer. Rejected
+	/// transfers credit the funds back to the holdings, while transfers with
+	/// unknown outcome stay pending. This includes transfers whose creation
+	/// time left the ledger's deduplication window, as the ledger cannot tell
+	/// whether they were executed before, so crediting their funds back could
+	/// pay them out twice. Those are settled via [Self::settle_withdrawal].
+	pub fn finish_withdrawal(
+		&mut self,
+		funding: &Funding,
+		result: core::result::Result<icp::BlockHeight, icp::PayoutError>,
+	) -> Result<WithdrawalReceipt> {
+		let entry = self
+			.withdrawals
+			.get_mut(funding)
+			.filter(|e| e.status == WithdrawalStatus::Pending)
+			.ok_or(Error::InvalidInput)?;
+		match result {
+			Ok(block) => {
+				entry.status = WithdrawalStatus::Completed(block);
+				let receipt = entry.receipt().unwrap();
+				self.mark_withdrawn(&funding.channel);
+				Ok(receipt)
+			}
+			Err(icp::PayoutError::Rejected) => {
+				entry.status = WithdrawalStatus::Failed;
+				let total = entry.amount.clone() + entry.fee.clone();
+				self.deposit(funding.clone(), total)?;
+				Err(Error::LedgerError)
+			}
+			Err(icp::PayoutError::Unknown | icp::PayoutError::Expired) => {
+				Err(Error::WithdrawalPending)
+			}
+		}
+	}
+
+	/// Settles a pending withdrawal on behalf of one of the canister's
+	/// administrators, who looked up on the ledger whether its transfer was
+	/// executed. Records the withdrawal as completed in the given block, or as
+	/// failed if there is none, crediting its funds back to the holdings.
+	/// Returns the receipt of completed withdrawals.
+	pub fn settle_withdrawal(
+		&mut self,
+		caller: &L1Account,
+		funding: &Funding,
+		block: Option<icp::BlockHeight>,
+	) -> Result<Option<WithdrawalReceipt>> {
+		require!(self.config.is_admin(caller), Authentication);
+		match self.finish_withdrawal(funding, block.ok_or(icp::PayoutError::Rejected)) {
+			Ok(receipt) => Ok(Some(receipt)),
+			Err(Error::LedgerError) => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	pub fn query_withdrawal(&self, funding: &Funding) -> Option<WithdrawalEntry> {
+		self.withdrawals.get(funding).cloned()
+	}
+
+	/// Certifies a channel's latest event.
+	pub fn certify_event(&mut self, record: &EventRecord) {
+		self.certified
+			.set(certification::events_key(&record.channel), Some(record));
+	}
+
+	/// Updates the certified latest events of channels whose stored events
+	/// were garbage collected. Channels without remaining events are removed
+	/// from the certified data tree.
+	pub fn certify_collected_events(
+		&mut self,
+		events: &LocalEventRegisterer,
+		channels: &[ChannelId],
+	) {
+		for ch in channels {
+			self.certified
+				.set(certification::events_key(ch), events.head(ch).as_ref());
+		}
+	}
+
+	/// The root hash of the certified data tree.
+	pub fn certified_root(&self) -> certification::TreeHash {
+		self.certified.root_hash()
+	}
+
+	/// Returns the witness for a key of the certified data tree.
+	pub fn witness(&self, key: &[u8]) -> Witness {
+		self.certified.witness(key)
+	}
+
+	fn certify_holdings(&mut self, funding: &Funding) {
+		self.certified.set(
+			certification::holdings_key(funding),
+			self.holdings.get(funding),
+		);
+	}
+
+	fn certify_state(&mut self, channel: &ChannelId) {
+		self.certified.set(
+			certification::state_key(channel),
+			self.channels.get(channel),
+		);
+	}
+
+	/// Checks that a withdrawal request is neither expired nor replayed.
+	fn check_replay(&self, req: &WithdrawalRequest, now: Timestamp) -> Result<()> {
+		if let Some(valid_until) = req.valid_until {
+			require!(now <= valid_until, WithdrawalExpired);
+		}
+		if let Some(&nonce) = self.nonces.get(&req.funding) {
+			require!(req.nonce > nonce, WithdrawalNonceUsed);
+		}
+		Ok(())
+	}
+
+	/// Checks that a withdrawal request is signed by the funds' owner and that
+	/// the channel is settled.
+	fn authorize_withdrawal(
+		&self,
+		req: &WithdrawalRequest,
+		auth: &L2Signature,
+		now: Timestamp,
+	) -> Result<()> {
+		req.validate_sig(auth, &self.principal)?;
+		self.require_settled(&req.funding.channel, now)
+	}
+
+	fn require_settled(&self, channel: &ChannelId, now: Timestamp) -> Result<()> {
+		match self.state(channel) {
 			None => Err(Error::NotFinalized),
 			Some(state) => {
-				require!(state.settled(now), NotFinalized);
-				Ok(self.holdings.remove(&req.funding).unwrap_or_default())
+				require!(self.settled(&state, now), NotFinalized);
+				Ok(())
 			}
 		}
 	}
@@ -0,0 +1,128 @@
+//  Copyright 2022 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use crate::{
+	events::{LocalEventRegisterer, Outbox},
+	icp::ReceiverState,
+	require,
+	subscriptions::Subscriptions,
+	types::*,
+};
+use candid::{Decode, Encode};
+use ic_cdk::api::stable::{stable_bytes, stable_size, StableWriter};
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+
+/// The layout version of the data written to stable memory. Has to be
+/// incremented whenever [StableState] changes incompatibly, in which case
+/// [decode] needs to migrate the previous layout.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Size of the header preceding the encoded state: the schema version (4 byte)
+/// and the payload length (8 byte), both little endian.
+const HEADER_SIZE: usize = 12;
+
+/// Stable memory handling errors.
+#[derive(PartialEq, Eq, CandidType, Deserialize, Debug)]
+pub enum StableMemoryError {
+	/// The header is missing or announces more data than is present.
+	Header,
+	/// The data was written with an unknown schema version.
+	UnsupportedVersion(u32),
+	/// The payload could not be decoded.
+	Decode,
+}
+
+impl std::fmt::Display for StableMemoryError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		std::fmt::Debug::fmt(self, f)
+	}
+}
+
+/// Everything that needs to survive a canister upgrade.
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct StableState {
+	/// The Perun canister's channels, holdings, withdrawals, scheduled
+	/// settlements and received transactions.
+	pub canister: CanisterSnapshot,
+	/// The locally stored channel events.
+	pub events: LocalEventRegisterer,
+	/// The events not yet forwarded to the event canister.
+	pub outbox: Outbox,
+	/// The event subscriptions and their pending deliveries.
+	pub subscriptions: Subscriptions,
+}
+
+/// The persistent part of a [crate::CanisterState].
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct CanisterSnapshot {
+	pub config: Config,
+	pub receiver: ReceiverState,
+	pub holdings: HashMap<Funding, Amount>,
+	pub channels: HashMap<ChannelId, RegisteredState>,
+	pub params: HashMap<ChannelId, Params>,
+	pub phases: HashMap<ChannelId, ChannelPhase>,
+	pub targets: HashMap<ChannelId, Vec<Amount>>,
+	pub withdrawals: HashMap<Funding, WithdrawalEntry>,
+	pub sweeps: HashMap<Funding, SweepEntry>,
+	pub nonces: HashMap<Funding, u64>,
+	pub timeouts: BTreeSet<(Timestamp, ChannelId)>,
+	pub payouts: HashMap<ChannelId, Vec<WithdrawalRequest>>,
+}
+
+/// Serializes a state, prefixed with the current schema version header.
+pub fn encode(state: &StableState) -> Vec<u8> {
+	let payload = Encode!(state).expect("encoding stable state");
+	let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+	bytes.extend_from_slice(&SCHEMA_VERSION.to_le_bytes());
+	bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+	bytes.extend(payload);
+	bytes
+}
+
+/// Deserializes a state that was serialized via [encode]. Trailing bytes after
+/// the announced payload length are ignored, as stable memory is allocated in
+/// whole pages.
+pub fn decode(bytes: &[u8]) -> Result<StableState, StableMemoryError> {
+	require!(bytes.len() >= HEADER_SIZE, StableMemoryError::Header);
+	let version = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+	let len = u64::from_le_bytes(bytes[4..HEADER_SIZE].try_into().unwrap());
+	require!(
+		len <= (bytes.len() - HEADER_SIZE) as u64,
+		StableMemoryError::Header
+	);
+	let payload = &bytes[HEADER_SIZE..HEADER_SIZE + len as usize];
+
+	match version {
+		SCHEMA_VERSION => Decode!(payload, StableState).map_err(|_| StableMemoryError::Decode),
+		v => Err(StableMemoryError::UnsupportedVersion(v)),
+	}
+}
+
+/// Writes a state into the canister's stable memory.
+pub fn save(state: &StableState) {
+	StableWriter::default()
+		.write_all(&encode(state))
+		.expect("writing stable memory");
+}
+
+/// Reads the state from the canister's stable memory. Returns nothing if the
+/// stable memory is empty, i.e., when upgrading from a version that did not
+/// persist its state.
+pub fn restore() -> Result<Option<StableState>, StableMemoryError> {
+	if stable_size() == 0 {
+		return Ok(None);
+	}
+	decode(&stable_bytes()).map(Some)
+}
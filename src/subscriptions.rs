@@ -0,0 +1,244 @@
+//  Copyright 2022 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use crate::error::{Error, Result};
+use crate::events::Event;
+use crate::require;
+use crate::types::*;
+use ic_cdk::export::Principal;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+lazy_static! {
+	pub static ref STATE: RwLock<Subscriptions> = RwLock::new(Subscriptions::default());
+}
+
+/// How many deliveries to a subscriber may fail in a row before it is
+/// unsubscribed.
+pub const MAX_FAILURES: u32 = 5;
+/// The delay before retrying a failed delivery, doubled with every further
+/// attempt.
+pub const RETRY_DELAY: Duration = 10 * 1_000_000_000;
+/// The maximum number of subscriptions per subscriber canister.
+pub const MAX_SUBSCRIPTIONS: usize = 100;
+/// The cycles a subscriber canister has to attach to each subscription, which
+/// pay for the deliveries and make spamming subscriptions costly.
+pub const SUBSCRIPTION_FEE: u64 = 100_000_000_000;
+/// The maximum number of deliveries sent per heartbeat, which keeps the
+/// heartbeat below the instruction limit.
+pub const MAX_DELIVERIES: usize = 100;
+/// The maximum number of deliveries queued per subscription. The oldest
+/// events are dropped first, so that an unresponsive subscriber cannot make
+/// the queue grow without bound before it is unsubscribed.
+pub const MAX_QUEUED: usize = 1000;
+
+#[derive(Clone, CandidType, Deserialize, PartialEq, Eq, Debug)]
+/// The events a subscription is notified about.
+pub enum Topic {
+	/// All events of a channel.
+	Channel(ChannelId),
+	/// All events of the channels in which a participant takes part, as far as
+	/// their parameters are known or their events name the participant, see
+	/// [crate::events::LocalEventRegisterer::add_members].
+	Participant(L2Account),
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+/// A canister method that is notified about events via calls with the
+/// arguments `(ChannelId, Timestamp, Event)`. A delivery only succeeds once the
+/// method replies.
+pub struct Subscription {
+	pub topic: Topic,
+	pub canister: Principal,
+	pub method: String,
+	/// The number of consecutive failed deliveries.
+	pub failures: u32,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+/// An event that still has to be delivered to a subscriber.
+pub struct Delivery {
+	pub subscription: u64,
+	pub channel: ChannelId,
+	pub time: Timestamp,
+	pub event: Event,
+	/// The number of failed attempts to deliver the event.
+	pub attempts: u32,
+	/// The earliest time of the next attempt.
+	pub due: Timestamp,
+}
+
+#[derive(Clone, Default, CandidType, Deserialize)]
+/// The subscriptions to the Perun canister's events, along with the events
+/// that still have to be delivered.
+pub struct Subscriptions {
+	subscriptions: BTreeMap<u64, Subscription>,
+	/// The id of the next subscription.
+	next_id: u64,
+	/// The pending deliveries.
+	queue: Vec<Delivery>,
+}
+
+impl Subscriptions {
+	/// Subscribes a method of the calling canister to the events of a topic,
+	/// given the cycles attached to the call, and returns the subscription's
+	/// id. The call has to attach at least the [SUBSCRIPTION_FEE].
+	pub fn subscribe(
+		&mut self,
+		canister: Principal,
+		topic: Topic,
+		method: String,
+		cycles: u64,
+	) -> Result<u64> {
+		require!(cycles >= SUBSCRIPTION_FEE, InsufficientCycles);
+		let count = self
+			.subscriptions
+			.values()
+			.filter(|s| s.canister == canister)
+			.count();
+		require!(count < MAX_SUBSCRIPTIONS, InvalidInput);
+
+		let id = self.next_id;
+		self.next_id += 1;
+		self.subscriptions.insert(
+			id,
+			Subscription {
+				topic,
+				canister,
+				method,
+				failures: 0,
+			},
+		);
+		Ok(id)
+	}
+
+	/// Removes a subscription on behalf of its subscriber canister.
+	pub fn unsubscribe(&mut self, canister: Principal, id: u64) -> Result<()> {
+		match self.subscriptions.get(&id) {
+			None => Err(Error::InvalidInput),
+			Some(s) if s.canister != canister => Err(Error::Authentication),
+			Some(_) => {
+				self.remove(id);
+				Ok(())
+			}
+		}
+	}
+
+	pub fn subscription(&self, id: u64) -> Option<&Subscription> {
+		self.subscriptions.get(&id)
+	}
+
+	/// Queues an event for delivery to all subscriptions of its channel or of
+	/// the given participants of the channel.
+	pub fn publish(
+		&mut self,
+		time: Timestamp,
+		channel: ChannelId,
+		event: Event,
+		members: &[L2Account],
+	) {
+		let ids: Vec<_> = self
+			.subscriptions
+			.iter()
+			.filter(|(_, sub)| match &sub.topic {
+				Topic::Channel(ch) => ch == &channel,
+				Topic::Participant(who) => members.contains(who),
+			})
+			.map(|(&id, _)| id)
+			.collect();
+		for id in ids {
+			self.enqueue(Delivery {
+				subscription: id,
+				channel: channel.clone(),
+				time,
+				event: event.clone(),
+				attempts: 0,
+				due: time,
+			});
+		}
+	}
+
+	/// Queues a delivery. If its subscription already has [MAX_QUEUED]
+	/// deliveries queued, the one of the oldest event is dropped.
+	fn enqueue(&mut self, delivery: Delivery) {
+		let queued: Vec<_> = self
+			.queue
+			.iter()
+			.enumerate()
+			.filter(|(_, d)| d.subscription == delivery.subscription)
+			.map(|(i, d)| (d.time, i))
+			.collect();
+		if queued.len() >= MAX_QUEUED {
+			// The delivery itself is dropped if its event is the oldest.
+			let (time, i) = queued.into_iter().min().unwrap();
+			if time > delivery.time {
+				return;
+			}
+			self.queue.remove(i);
+		}
+		self.queue.push(delivery);
+	}
+
+	/// Removes up to [MAX_DELIVERIES] due deliveries from the queue and returns
+	/// them along with their subscriptions, in queue order. The outcome of each
+	/// returned delivery has to be recorded via [Subscriptions::delivered].
+	pub fn take_due(&mut self, now: Timestamp) -> Vec<(Subscription, Delivery)> {
+		let mut due = vec![];
+		let mut pending = vec![];
+		for d in std::mem::take(&mut self.queue) {
+			if d.due <= now && due.len() < MAX_DELIVERIES {
+				due.push(d);
+			} else {
+				pending.push(d);
+			}
+		}
+		self.queue = pending;
+		due.into_iter()
+			// Deliveries of subscriptions removed meanwhile are dropped.
+			.filter_map(|d| Some((self.subscriptions.get(&d.subscription)?.clone(), d)))
+			.collect()
+	}
+
+	/// Records whether the subscriber replied to a delivery taken via
+	/// [Subscriptions::take_due]. Failed deliveries are retried with exponential backoff, and
+	/// subscribers whose deliveries failed [MAX_FAILURES] times in a row are
+	/// unsubscribed.
+	pub fn delivered(&mut self, mut delivery: Delivery, success: bool, now: Timestamp) {
+		let sub = match self.subscriptions.get_mut(&delivery.subscription) {
+			Some(sub) => sub,
+			// Unsubscribed by an earlier failed delivery.
+			None => return,
+		};
+		if success {
+			sub.failures = 0;
+			return;
+		}
+
+		sub.failures += 1;
+		if sub.failures >= MAX_FAILURES {
+			self.remove(delivery.subscription);
+			return;
+		}
+		delivery.due = now + (RETRY_DELAY << delivery.attempts.min(16));
+		delivery.attempts += 1;
+		self.enqueue(delivery);
+	}
+
+	/// Removes a subscription and its pending deliveries.
+	fn remove(&mut self, id: u64) {
+		self.subscriptions.remove(&id);
+		self.queue.retain(|d| d.subscription != id);
+	}
+}
@@ -13,8 +13,9 @@
 //  limitations under the License.
 
 use candid::Encode;
-use ed25519_dalek::{ExpandedSecretKey, SecretKey};
+use ed25519_dalek::{Keypair, SecretKey};
 use ic_cdk::export::Principal;
+use k256::ecdsa::SigningKey;
 use oorandom::Rand64 as Prng;
 use std::time::SystemTime;
 
@@ -28,8 +29,11 @@ use crate::{types::*, CanisterState};
 /// canister calls.
 pub struct Setup {
 	pub parts: Vec<L2Account>,
-	pub secrets: Vec<ExpandedSecretKey>,
+	pub secrets: Vec<Box<dyn L2Signer>>,
 	pub canister: CanisterState<crate::icp::MockTXQuerier>,
+	/// The principal of the canister that the setup's signatures are bound
+	/// to. Has to be changed when calling a deployed canister.
+	pub canister_id: L1Account,
 	pub params: Params,
 	pub state: State,
 	pub prng: Prng,
@@ -44,19 +48,47 @@ pub fn rand_hash(rng: &mut Prng) -> Hash {
 	Hash::digest(&rng.rand_u64().to_ne_bytes())
 }
 
-/// Generates a public key pair from a randomness seed and an index.
-fn rand_key(rand: &mut Prng) -> (ExpandedSecretKey, L2Account) {
+/// Generates an ICRC-1 asset with a random ledger principal.
+pub fn rand_asset(rng: &mut Prng) -> Asset {
+	Asset {
+		ledger: L1Account::from_slice(&rng.rand_u64().to_be_bytes()),
+		standard: TokenStandard::ICRC1,
+	}
+}
+
+/// The signature schemes of layer-2 accounts.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scheme {
+	Ed25519,
+	Secp256k1,
+}
+
+/// Generates 32 random bytes.
+fn rand_bytes(rand: &mut Prng) -> [u8; 32] {
 	let bytes64: [u64; 4] = [
 		rand.rand_u64(),
 		rand.rand_u64(),
 		rand.rand_u64(),
 		rand.rand_u64(),
 	];
-	let bytes8: [u8; 32] = unsafe { std::mem::transmute(bytes64) };
-	let sk = SecretKey::from_bytes(&bytes8).unwrap();
-	let esk = ExpandedSecretKey::from(&sk);
-	let pk = L2Account((&sk).into());
-	(esk, pk)
+	unsafe { std::mem::transmute(bytes64) }
+}
+
+/// Generates a random key pair of the given signature scheme.
+pub fn rand_signer(rand: &mut Prng, scheme: Scheme) -> Box<dyn L2Signer> {
+	match scheme {
+		Scheme::Ed25519 => {
+			let secret = SecretKey::from_bytes(&rand_bytes(rand)).unwrap();
+			let public = (&secret).into();
+			Box::new(Keypair { secret, public })
+		}
+		// Retry in the unlikely case that the bytes are not a valid scalar.
+		Scheme::Secp256k1 => loop {
+			if let Ok(sk) = SigningKey::from_bytes(&rand_bytes(rand)) {
+				return Box::new(sk);
+			}
+		},
+	}
 }
 
 static SEED_ENV_VAR: &str = "PERUN_TEST_SEED";
@@ -75,39 +107,78 @@ fn seed() -> u128 {
 
 impl Setup {
 	pub fn new(finalized: bool, funded: bool) -> Self {
-		Self::with_rng(Prng::new(seed()), finalized, funded)
+		Self::with_rng(Prng::new(seed()), 1, finalized, funded)
+	}
+
+	/// Creates a randomised test setup for a channel holding the requested
+	/// number of assets.
+	pub fn with_assets(assets: usize, finalized: bool, funded: bool) -> Self {
+		Self::with_rng(Prng::new(seed()), assets, finalized, funded)
 	}
 
 	/// Creates a randomised test setup depending on the provided randomness
-	/// seed. The `finalized` flag controls whether the generated channel state
-	/// is final. The `funded` flag controls whether the outcome of the
+	/// seed. The first asset is always ICP, all further assets are ICRC-1
+	/// tokens. The `finalized` flag controls whether the generated channel
+	/// state is final. The `funded` flag controls whether the outcome of the
 	/// generated channel state should be deposited in the canister already.
-	pub fn with_rng(mut rand: Prng, finalized: bool, funded: bool) -> Self {
-		let key0 = rand_key(&mut rand);
-		let key1 = rand_key(&mut rand);
-		let parts = vec![key0.1, key1.1];
-		let secrets = vec![key0.0, key1.0];
+	/// The first participant has an Ed25519 key and the second a secp256k1
+	/// key, so that both signature schemes are covered.
+	pub fn with_rng(mut rand: Prng, assets: usize, finalized: bool, funded: bool) -> Self {
+		let secrets = vec![
+			rand_signer(&mut rand, Scheme::Ed25519),
+			rand_signer(&mut rand, Scheme::Secp256k1),
+		];
+		let parts: Vec<_> = secrets.iter().map(|s| s.account()).collect();
+
+		let mut asset_list = vec![Asset::default()];
+		while asset_list.len() < assets {
+			asset_list.push(rand_asset(&mut rand));
+		}
 
 		let params = Params {
 			nonce: rand_hash(&mut rand),
 			participants: parts.clone(),
+			assets: asset_list,
 			challenge_duration: 1,
+			principals: vec![],
+			app: None,
 		};
 
 		let state = State {
 			channel: params.id(),
 			version: rand.rand_u64(),
-			allocation: vec![
-				(rand.rand_u64() >> 20).into(),
-				(rand.rand_u64() >> 20).into(),
-			],
+			allocation: params
+				.assets
+				.iter()
+				.map(|_| {
+					vec![
+						(rand.rand_u64() >> 20).into(),
+						(rand.rand_u64() >> 20).into(),
+					]
+				})
+				.collect(),
 			finalized,
+			data: vec![],
+			locked: vec![],
+		};
+
+		let config = Config {
+			assets: params
+				.assets
+				.iter()
+				.map(|asset| AssetConfig {
+					asset: asset.clone(),
+					fee: crate::icp::DEFAULT_FEE_E8S.into(),
+				})
+				.collect(),
+			..Default::default()
 		};
 
 		let mut s = Setup {
 			parts,
 			secrets,
-			canister: CanisterState::new(Default::default(), Principal::anonymous()),
+			canister: CanisterState::new(Default::default(), Principal::anonymous(), config),
+			canister_id: Principal::anonymous(),
 			params,
 			state,
 			prng: rand,
@@ -117,37 +188,94 @@ impl Setup {
 			return s;
 		}
 
-		for (i, _) in s.parts.iter().enumerate() {
-			s.canister
-				.deposit(s.funding(i), s.state.allocation[i].clone())
-				.unwrap();
+		for a in 0..s.params.assets.len() {
+			for i in 0..s.parts.len() {
+				s.canister
+					.deposit(s.funding_for(a, i), s.state.allocation[a][i].clone())
+					.unwrap();
+			}
 		}
 		s
 	}
 
 	/// Signs the setup's channel state for all channel participants.
 	pub fn sign_state(&self) -> FullySignedState {
-		self.sign_encoding(&Encode!(&self.state).unwrap())
+		self.sign_encoding(&self.state.sig_message(&self.canister_id))
+	}
+
+	/// Signs a channel state for a single participant.
+	pub fn sign_state_by(&self, state: &State, part: usize) -> L2Signature {
+		self.secrets[part].sign(&state.sig_message(&self.canister_id))
 	}
+
+	/// Creates a sub-channel of the setup's channel between the same
+	/// participants, in a final state with the given allocation and
+	/// sub-allocations. Returns the sub-channel along with the sub-allocation
+	/// that locks its funds in its parent channel.
+	pub fn subchannel(
+		&mut self,
+		allocation: Vec<Vec<Amount>>,
+		locked: Vec<SubAlloc>,
+	) -> (SubChannel, SubAlloc) {
+		let params = Params {
+			nonce: rand_hash(&mut self.prng),
+			..self.params.clone()
+		};
+		let state = State {
+			channel: params.id(),
+			version: 1,
+			allocation,
+			finalized: true,
+			data: vec![],
+			locked,
+		};
+		let sub = SubAlloc {
+			id: params.id(),
+			balances: state.total(),
+			index_map: (0..self.parts.len() as u16).collect(),
+		};
+		let sigs = (0..self.parts.len())
+			.map(|i| self.sign_state_by(&state, i))
+			.collect();
+		let state = FullySignedState { state, sigs };
+		(SubChannel { params, state }, sub)
+	}
+
 	/// Creates a fully signed state with invalid signatures.
 	pub fn sign_state_invalid(&self) -> FullySignedState {
 		self.sign_encoding(&Encode!(&"invalid state").unwrap())
 	}
 
-	/// Returns the funding for a participant.
+	/// Returns the funding of the first asset for a participant.
 	pub fn funding(&self, part: usize) -> Funding {
-		Funding::new(self.params.id(), self.parts[part].clone())
+		self.funding_for(0, part)
+	}
+
+	/// Returns the funding of an asset for a participant.
+	pub fn funding_for(&self, asset: usize, part: usize) -> Funding {
+		Funding::new(
+			self.params.id(),
+			self.parts[part].clone(),
+			self.params.assets[asset].clone(),
+		)
 	}
 
 	/// Creates a signed withdrawal request of the setup's channel for a given
-	/// participant and receiver.
+	/// participant and receiver, with nonce 0.
 	pub fn withdrawal_to(
 		&self,
 		part: usize,
 		receiver: L1Account,
 	) -> (WithdrawalRequest, L2Signature) {
 		let funding = self.funding(part);
-		let req = WithdrawalRequest::new(funding, receiver);
+		let req = WithdrawalRequest::new(funding, receiver, 0);
+		(req.clone(), self.sign_withdrawal(&req, part))
+	}
+
+	/// Creates a signed withdrawal request with a preset receiver and the
+	/// given nonce.
+	pub fn withdrawal_nonce(&self, part: usize, nonce: u64) -> (WithdrawalRequest, L2Signature) {
+		let req = WithdrawalRequest::new(self.funding(part), default_account(), nonce);
 		(req.clone(), self.sign_withdrawal(&req, part))
 	}
 
@@ -159,13 +287,7 @@ impl Setup {
 	/// Manually signs a withdrawal request using the requested participant's
 	/// secret key.
 	pub fn sign_withdrawal(&self, req: &WithdrawalRequest, part: usize) -> L2Signature {
-		let enc = Encode!(req).unwrap();
-		L2Signature(
-			self.secrets[part]
-				.sign(&enc, &self.parts[part].0)
-				.to_bytes()
-				.into(),
-		)
+		self.secrets[part].sign(&req.sig_message(&self.canister_id))
 	}
 
 	/// Creates a fully signed state from the setup's state and uses the given
@@ -173,10 +295,8 @@ impl Setup {
 	fn sign_encoding(&self, enc: &[u8]) -> FullySignedState {
 		let mut state = FullySignedState::default();
 		state.state = self.state.clone();
-		for (i, key) in self.parts.iter().enumerate() {
-			state.sigs.push(L2Signature(
-				self.secrets[i].sign(enc, &key.0).to_bytes().into(),
-			))
+		for secret in self.secrets.iter() {
+			state.sigs.push(secret.sign(enc));
 		}
 
 		state
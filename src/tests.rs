@@ -15,14 +15,22 @@
 use crate::*;
 use assert::assert_ok;
 
+/// Runs a future to completion, for testing the canister's async methods.
+fn block_on<F: std::future::Future>(f: F) -> F::Output {
+	tokio::runtime::Builder::new_current_thread()
+		.build()
+		.unwrap()
+		.block_on(f)
+}
+
 #[test]
 /// Tests that repeated deposits are added correctly and that only the specified
 /// participant is credited. Also tests the `query_holdings()` method.
 fn test_deposit() {
 	let mut s = test::Setup::new(false, false);
 
-	let funding = Funding::new(s.params.id(), s.parts[0].clone());
-	let funding2 = Funding::new(s.params.id(), s.parts[1].clone());
+	let funding = s.funding(0);
+	let funding2 = s.funding(1);
 	// No deposits yet.
 	assert_eq!(s.canister.query_holdings(funding.clone()), None);
 	assert_eq!(s.canister.query_holdings(funding2.clone()), None);
@@ -89,7 +97,7 @@ fn test_conclude_not_signed() {
 /// Tests that underfunded channels cannot be concluded.
 fn test_conclude_insufficient_funds() {
 	let mut s = test::Setup::new(true, true);
-	s.state.allocation[0] += 1000;
+	s.state.allocation[0][0] += 1000;
 	let sstate = s.sign_state();
 	assert_eq!(
 		s.canister.conclude(s.params, sstate, 0),
@@ -101,7 +109,19 @@ fn test_conclude_insufficient_funds() {
 /// Tests that invalid sized allocations are rejected.
 fn test_conclude_invalid_allocation() {
 	let mut s = test::Setup::new(true, true);
-	s.state.allocation.push(5.into());
+	s.state.allocation[0].push(5.into());
+	let signed = s.sign_state();
+	assert_eq!(
+		s.canister.conclude(s.params, signed, 0),
+		Err(Error::InvalidInput)
+	);
+}
+
+#[test]
+/// Tests that allocations must contain balances for all assets of a channel.
+fn test_conclude_missing_asset_allocation() {
+	let mut s = test::Setup::with_assets(2, true, true);
+	s.state.allocation.pop();
 	let signed = s.sign_state();
 	assert_eq!(
 		s.canister.conclude(s.params, signed, 0),
@@ -109,6 +129,54 @@ fn test_conclude_invalid_allocation() {
 	);
 }
 
+#[test]
+/// Tests that a multi-asset channel cannot be concluded if only one of its
+/// assets is underfunded.
+fn test_conclude_multi_asset_insufficient_funds() {
+	let mut s = test::Setup::with_assets(2, true, true);
+	s.state.allocation[1][1] += 1;
+	let sstate = s.sign_state();
+	assert_eq!(
+		s.canister.conclude(s.params, sstate, 0),
+		Err(Error::InsufficientFunding)
+	);
+}
+
+#[test]
+/// Tests that channels listing an asset twice are rejected, as their funds
+/// would be counted twice.
+fn test_conclude_duplicate_asset() {
+	let mut s = test::Setup::new(true, true);
+	let asset = s.params.assets[0].clone();
+	s.params.assets.push(asset);
+	s.state.channel = s.params.id();
+	let balances = s.state.allocation[0].clone();
+	s.state.allocation.push(balances);
+	let sstate = s.sign_state();
+	assert_eq!(
+		s.canister.conclude(s.params, sstate, 0),
+		Err(Error::InvalidInput)
+	);
+}
+
+#[test]
+/// Tests that channels holding assets not supported by the canister are
+/// rejected.
+fn test_conclude_unsupported_asset() {
+	let mut s = test::Setup::new(true, true);
+	let asset = test::rand_asset(&mut s.prng);
+	s.params.assets.push(asset);
+	s.state.channel = s.params.id();
+	s.state
+		.allocation
+		.push(vec![Amount::default(), Amount::default()]);
+	let sstate = s.sign_state();
+	assert_eq!(
+		s.canister.conclude(s.params, sstate, 0),
+		Err(Error::UnsupportedAsset)
+	);
+}
+
 #[test]
 /// Tests that a dispute with a nonfinal state will register the state properly
 /// but not mark it as final yet.
@@ -196,7 +264,7 @@ fn test_dispute_underfunded_initial_state() {
 	let mut time = 0;
 	let mut s = test::Setup::new(false, false);
 
-	let amount = s.state.allocation[0].clone();
+	let amount = s.state.allocation[0][0].clone();
 	// only fund one participant.
 	assert_ok!(s.canister.deposit(s.funding(0), amount.clone()));
 
@@ -232,20 +300,49 @@ fn test_dispute_underfunded_initial_state() {
 /// Tests that the total deposits are properly tracked.
 fn test_holding_tracking_deposit() {
 	let s = test::Setup::new(true, true);
-	let sum = s.state.allocation[0].clone() + s.state.allocation[1].clone();
-	assert_eq!(s.canister.holdings_total(&s.params), sum);
+	let sum = s.state.allocation[0][0].clone() + s.state.allocation[0][1].clone();
+	assert_eq!(s.canister.holdings_total(&s.params), vec![sum]);
+}
+
+#[test]
+/// Tests that the total deposits are tracked separately for each asset.
+fn test_holding_tracking_multi_asset() {
+	let s = test::Setup::with_assets(3, true, true);
+	assert_eq!(s.canister.holdings_total(&s.params), s.state.total());
+	assert_eq!(s.state.total().len(), 3);
+}
+
+#[test]
+/// Tests the happy case for withdrawing each asset of a multi-asset channel.
+fn test_withdraw_multi_asset() {
+	let mut s = test::Setup::with_assets(2, true, true);
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+
+	for a in 0..2 {
+		let req = WithdrawalRequest::new(s.funding_for(a, 0), test::default_account(), 0);
+		let sig = s.sign_withdrawal(&req, 0);
+		assert_eq!(
+			s.canister.withdraw(req, sig, 0),
+			Ok(s.state.allocation[a][0].clone())
+		);
+	}
 }
 
 #[test]
 /// Tests that unregistered channels are counted as unfunded.
 fn test_holding_tracking_none() {
 	let s = test::Setup::new(true, false);
-	assert_eq!(s.canister.holdings_total(&s.params), 0);
+	assert_eq!(
+		s.canister.holdings_total(&s.params),
+		vec![Amount::default()]
+	);
 }
 
 #[test]
 /// Tests the happy case for withdrawing funds from a settled channel. Also
-/// tests that redundant withdrawals will not withdraw any additional funds.
+/// tests that redundant withdrawals will not withdraw any additional funds and
+/// that withdrawal requests cannot be replayed.
 fn test_withdraw() {
 	let mut s = test::Setup::new(true, true);
 	let sstate = s.sign_state();
@@ -259,7 +356,13 @@ fn test_withdraw() {
 		Ok(holdings)
 	);
 
+	// Test that replayed withdrawals are rejected.
+	assert_eq!(
+		s.canister.withdraw(req, sig, 0),
+		Err(Error::WithdrawalNonceUsed)
+	);
 	// Test that repeated withdraws return nothing.
+	let (req, sig) = s.withdrawal_nonce(0, 1);
 	assert_eq!(s.canister.withdraw(req, sig, 0), Ok(Amount::default()));
 }
 
@@ -310,3 +413,1918 @@ fn test_withdraw_not_finalized() {
 
 	assert_eq!(s.canister.withdraw(req, sig, 0), Err(Error::NotFinalized));
 }
+
+#[test]
+/// Tests that events are forwarded to the event canister in order, one at a
+/// time, that events whose forwarding failed stay queued, and that a call whose
+/// outcome was never recorded only blocks the outbox until it times out.
+fn test_event_outbox() {
+	let mut outbox = events::Outbox::default();
+	outbox.push(1, Default::default(), Event::Concluded, vec![]);
+	outbox.push(2, Default::default(), Event::Concluded, vec![]);
+
+	assert_eq!(
+		outbox.begin(0).map(|(seq, time, ..)| (seq, time)),
+		Some((0, 1))
+	);
+	assert!(outbox.begin(0).is_none());
+	outbox.finish(0, false);
+	assert_eq!(outbox.begin(0).map(|(seq, ..)| seq), Some(0));
+	outbox.finish(0, true);
+	assert_eq!(
+		outbox.begin(0).map(|(seq, time, ..)| (seq, time)),
+		Some((1, 2))
+	);
+
+	// The callback trapped without recording the outcome.
+	assert!(outbox.begin(events::SEND_TIMEOUT - 1).is_none());
+	assert_eq!(
+		outbox.begin(events::SEND_TIMEOUT).map(|(seq, ..)| seq),
+		Some(1)
+	);
+	outbox.finish(1, true);
+	assert!(outbox.begin(events::SEND_TIMEOUT).is_none());
+}
+
+#[test]
+/// Tests that the event canister rejects events of other principals than the
+/// Perun canister, so that they stay in the Perun canister's outbox.
+fn test_event_canister_caller() {
+	let perun = Principal::from_slice(&[1]);
+	let mut state = events::CanisterState::new(perun, None);
+	assert_eq!(
+		state.register_event(
+			&Principal::anonymous(),
+			0,
+			1,
+			Default::default(),
+			Event::Concluded,
+			&[]
+		),
+		Err(Error::Authentication)
+	);
+	assert!(state.all_events(0, 10).events.is_empty());
+	assert_ok!(state.register_event(&perun, 0, 1, Default::default(), Event::Concluded, &[]));
+	assert_eq!(state.all_events(0, 10).events.len(), 1);
+}
+
+#[test]
+/// Tests that the event canister ignores events it already stored, as they are
+/// sent again if the reply to their call got lost, and rejects events that
+/// arrive out of order.
+fn test_event_canister_dedup() {
+	let perun = Principal::from_slice(&[1]);
+	let mut state = events::CanisterState::new(perun, None);
+	let ch = ChannelId::default();
+	// The first event may have any sequence number, as the Perun canister may
+	// have forwarded events elsewhere before.
+	assert_ok!(state.register_event(&perun, 5, 1, ch.clone(), Event::Concluded, &[]));
+	assert_ok!(state.register_event(&perun, 5, 1, ch.clone(), Event::Concluded, &[]));
+	assert_eq!(state.all_events(0, 10).events.len(), 1);
+	assert_eq!(
+		state.register_event(&perun, 7, 2, ch.clone(), Event::Concluded, &[]),
+		Err(Error::InvalidInput)
+	);
+	assert_ok!(state.register_event(&perun, 6, 2, ch.clone(), Event::Concluded, &[]));
+	assert_ok!(state.register_event(&perun, 4, 0, ch, Event::Concluded, &[]));
+	assert_eq!(state.all_events(0, 10).events.len(), 2);
+}
+
+#[test]
+/// Tests that a populated canister state survives the stable memory
+/// serialization used during upgrades.
+fn test_stable_state_roundtrip() {
+	let mut s = test::Setup::new(false, true);
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.dispute(s.params.clone(), sstate, 0));
+
+	let bytes = stable::encode(&stable::StableState {
+		canister: s.canister.snapshot(),
+		events: Default::default(),
+		outbox: Default::default(),
+		subscriptions: Default::default(),
+	});
+	let decoded = stable::decode(&bytes).unwrap();
+	let mut restored = CanisterState::new(
+		Default::default(),
+		Principal::anonymous(),
+		Default::default(),
+	);
+	restored.restore(decoded.canister);
+
+	for i in 0..s.parts.len() {
+		assert_eq!(
+			restored.query_holdings(s.funding(i)),
+			s.canister.query_holdings(s.funding(i))
+		);
+	}
+	let channel = restored.state(&s.params.id()).unwrap();
+	assert_eq!(channel.state.version, s.state.version);
+	assert_eq!(channel.timeout, s.params.challenge_duration);
+	assert_eq!(restored.holdings_total(&s.params), s.state.total());
+}
+
+#[test]
+/// Tests that stable memory written with an unknown schema version is rejected.
+fn test_stable_state_unknown_version() {
+	let mut bytes = stable::encode(&Default::default());
+	bytes[0..4].copy_from_slice(&(stable::SCHEMA_VERSION + 1).to_le_bytes());
+	assert_eq!(
+		stable::decode(&bytes).err(),
+		Some(stable::StableMemoryError::UnsupportedVersion(
+			stable::SCHEMA_VERSION + 1
+		))
+	);
+	assert_eq!(
+		stable::decode(&bytes[..4]).err(),
+		Some(stable::StableMemoryError::Header)
+	);
+}
+
+#[test]
+/// Tests that ICRC-1 transfers and mints are converted into transaction
+/// notifications and that unsupported memos are rejected.
+fn test_icrc1_transaction_notification() {
+	let to = icrc1::Account {
+		owner: Principal::anonymous(),
+		subaccount: None,
+	};
+	let transfer = icrc1::Transfer {
+		from: to.clone(),
+		to: to.clone(),
+		amount: 42u64.into(),
+		fee: None,
+		memo: Some(icrc1::encode_memo(1337)),
+		created_at_time: None,
+	};
+	let mut tx = icrc1::Transaction {
+		kind: "transfer".into(),
+		mint: None,
+		transfer: Some(transfer.clone()),
+		timestamp: 0,
+	};
+
+	let n = tx.clone().notification().unwrap();
+	assert_eq!(n.from, Some(to.identifier()));
+	assert_eq!(n.to, to.identifier());
+	assert_eq!(n.amount, 42);
+	assert_eq!(n.memo, 1337);
+
+	tx.transfer.as_mut().unwrap().memo = Some(vec![1, 2, 3].into());
+	assert!(tx.clone().notification().is_none());
+
+	// A missing memo is treated as 0.
+	tx.transfer.as_mut().unwrap().memo = None;
+	assert_eq!(tx.clone().notification().unwrap().memo, 0);
+
+	tx.transfer = None;
+	assert!(tx.notification().is_none());
+}
+
+#[test]
+/// Tests that deposit subaccounts are unique per channel, participant and
+/// asset.
+fn test_funding_subaccount() {
+	let mut s = test::Setup::with_assets(2, false, false);
+	let sub = s.funding(0).subaccount();
+	assert_eq!(sub, s.funding(0).subaccount());
+	assert_ne!(sub, s.funding_for(1, 0).subaccount());
+	assert_ne!(sub, s.funding(1).subaccount());
+
+	let mut other = s.funding(0);
+	other.channel = test::rand_hash(&mut s.prng);
+	assert_ne!(sub, other.subaccount());
+
+	// Assets sharing a ledger have separate deposit addresses.
+	let mut other = s.funding(0);
+	other.asset.standard = match other.asset.standard {
+		TokenStandard::ICP => TokenStandard::ICRC1,
+		TokenStandard::ICRC1 => TokenStandard::ICP,
+	};
+	assert_ne!(sub, other.subaccount());
+
+	let address = ledger::DepositAddress::new(Principal::anonymous(), &s.funding(0));
+	assert_eq!(address.account.subaccount, Some(sub));
+	assert_eq!(address.account_identifier, address.account.identifier());
+}
+
+#[test]
+/// Tests that withdrawals not exceeding the fee leave the holdings untouched.
+fn test_withdraw_below_fee() {
+	let mut s = test::Setup::new(true, false);
+	let fee = s.canister.config().fee(&s.params.assets[0]).unwrap();
+	s.state.allocation[0] = vec![fee.clone(), Amount::default()];
+	assert_ok!(s.canister.deposit(s.funding(0), fee.clone()));
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+
+	let (req, sig) = s.withdrawal(0);
+	assert_eq!(
+		s.canister.begin_withdrawal(req, sig, 0),
+		Err(Error::WithdrawalBelowFee)
+	);
+	assert_eq!(s.canister.query_holdings(s.funding(0)), Some(fee));
+	assert_eq!(s.canister.query_withdrawal(&s.funding(0)), None);
+}
+
+#[test]
+/// Tests that withdrawals with unknown outcome stay pending, even once they
+/// are too old to be deduplicated, and are repeated with identical arguments
+/// instead of being paid out again.
+fn test_withdrawal_journal_pending() {
+	let mut s = test::Setup::new(true, true);
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+
+	let funding = s.funding(0);
+	let fee = s.canister.config().fee(&funding.asset).unwrap();
+	let holdings = s.canister.query_holdings(funding.clone()).unwrap();
+	let (req, sig) = s.withdrawal(0);
+	let entry = s
+		.canister
+		.begin_withdrawal(req.clone(), sig.clone(), 7)
+		.unwrap();
+	assert_eq!(entry.status, WithdrawalStatus::Pending);
+	assert_eq!(entry.created_at_time, 7);
+	// The ledger fee is deducted from the withdrawn funds.
+	assert_eq!(
+		(entry.amount.clone(), entry.fee.clone()),
+		(holdings - fee.clone(), fee)
+	);
+	assert_eq!(s.canister.query_holdings(funding.clone()), None);
+
+	assert_eq!(
+		s.canister
+			.finish_withdrawal(&funding, Err(icp::PayoutError::Unknown)),
+		Err(Error::WithdrawalPending)
+	);
+	assert_eq!(s.canister.query_withdrawal(&funding), Some(entry.clone()));
+	// The holdings are not credited back.
+	assert_eq!(s.canister.query_holdings(funding.clone()), None);
+	// Neither if the transfer is too old to be deduplicated.
+	assert_eq!(
+		s.canister
+			.finish_withdrawal(&funding, Err(icp::PayoutError::Expired)),
+		Err(Error::WithdrawalPending)
+	);
+	assert_eq!(s.canister.query_withdrawal(&funding), Some(entry.clone()));
+
+	// Retrying repeats the pending transfer.
+	assert_eq!(s.canister.begin_withdrawal(req, sig, 9), Ok(entry.clone()));
+	let receipt = s.canister.finish_withdrawal(&funding, Ok(3)).unwrap();
+	assert_eq!(receipt.block, 3);
+	assert_eq!(
+		s.canister.query_withdrawal(&funding).unwrap().status,
+		WithdrawalStatus::Completed(3)
+	);
+}
+
+#[test]
+/// Tests that administrators settle pending withdrawals as completed or as
+/// failed, which credits the funds back to the holdings.
+fn test_settle_withdrawal() {
+	let mut s = test::Setup::new(true, true);
+	let admin = Principal::from_slice(&[1]);
+	s.canister.config.admins.push(admin);
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+
+	let funding = s.funding(0);
+	let holdings = s.canister.query_holdings(funding.clone());
+	let (req, sig) = s.withdrawal(0);
+	assert_ok!(s.canister.begin_withdrawal(req, sig, 0));
+	assert_eq!(
+		s.canister
+			.finish_withdrawal(&funding, Err(icp::PayoutError::Expired)),
+		Err(Error::WithdrawalPending)
+	);
+	assert_eq!(
+		s.canister
+			.settle_withdrawal(&Principal::anonymous(), &funding, None),
+		Err(Error::Authentication)
+	);
+	assert_eq!(
+		s.canister.settle_withdrawal(&admin, &funding, None),
+		Ok(None)
+	);
+	assert_eq!(
+		s.canister.query_withdrawal(&funding).unwrap().status,
+		WithdrawalStatus::Failed
+	);
+	assert_eq!(s.canister.query_holdings(funding.clone()), holdings);
+	// Only pending withdrawals can be settled.
+	assert_eq!(
+		s.canister.settle_withdrawal(&admin, &funding, Some(3)),
+		Err(Error::InvalidInput)
+	);
+
+	let (req, sig) = s.withdrawal_nonce(0, 1);
+	let entry = s.canister.begin_withdrawal(req, sig, 0).unwrap();
+	let receipt = s
+		.canister
+		.settle_withdrawal(&admin, &funding, Some(3))
+		.unwrap()
+		.unwrap();
+	assert_eq!((receipt.amount, receipt.block), (entry.amount, 3));
+	assert_eq!(
+		s.canister.query_withdrawal(&funding).unwrap().status,
+		WithdrawalStatus::Completed(3)
+	);
+	assert_eq!(s.canister.query_holdings(funding), None);
+}
+
+#[test]
+/// Tests that rejected withdrawals credit the funds back to the holdings.
+fn test_withdrawal_journal_rejected() {
+	let mut s = test::Setup::new(true, true);
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+
+	let funding = s.funding(0);
+	let holdings = s.canister.query_holdings(funding.clone());
+	let (req, sig) = s.withdrawal(0);
+	assert_ok!(s.canister.begin_withdrawal(req, sig, 0));
+	assert_eq!(
+		s.canister
+			.finish_withdrawal(&funding, Err(icp::PayoutError::Rejected)),
+		Err(Error::LedgerError)
+	);
+	assert_eq!(
+		s.canister.query_withdrawal(&funding).unwrap().status,
+		WithdrawalStatus::Failed
+	);
+	assert_eq!(s.canister.query_holdings(funding.clone()), holdings);
+
+	// Only pending withdrawals can be finished.
+	assert_eq!(
+		s.canister.finish_withdrawal(&funding, Ok(0)),
+		Err(Error::InvalidInput)
+	);
+}
+
+#[test]
+/// Tests that disputes are settled once they time out and that registered
+/// payouts are started then.
+fn test_settle_expired_dispute() {
+	let mut s = test::Setup::new(false, true);
+	let channel = s.params.id();
+	let funding = s.funding(0);
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.dispute(s.params.clone(), sstate, 0));
+	let (req, sig) = s.withdrawal(0);
+	assert_ok!(s.canister.register_payout(req, sig, 0));
+	let timeout = s.canister.state(&channel).unwrap().timeout;
+
+	// Nothing is settled before the timeout.
+	assert_eq!(s.canister.settle_expired(timeout - 1), (vec![], vec![]));
+
+	let (concluded, payouts) = s.canister.settle_expired(timeout);
+	assert_eq!(concluded, vec![channel]);
+	assert_eq!(payouts.len(), 1);
+	assert_eq!(payouts[0].0, funding);
+	assert_eq!(payouts[0].1.status, WithdrawalStatus::Pending);
+	assert_eq!(s.canister.query_holdings(funding.clone()), None);
+	assert_eq!(
+		s.canister.query_withdrawal(&funding),
+		Some(payouts[0].1.clone())
+	);
+
+	// Settlements happen only once.
+	assert_eq!(s.canister.settle_expired(timeout), (vec![], vec![]));
+}
+
+#[test]
+/// Tests that payouts can only be registered by participants of announced or
+/// registered channels.
+fn test_register_payout_participants() {
+	let mut s = test::Setup::new(false, true);
+	let (req, sig) = s.withdrawal(0);
+	assert_eq!(
+		s.canister.register_payout(req.clone(), sig.clone(), 0),
+		Err(Error::InvalidInput)
+	);
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.dispute(s.params.clone(), sstate, 0));
+
+	// Someone else's key cannot register payouts for the channel.
+	let outsider = test::rand_signer(&mut s.prng, test::Scheme::Ed25519);
+	let mut funding = s.funding(0);
+	funding.participant = outsider.account();
+	let other = WithdrawalRequest::new(funding, test::default_account(), 0);
+	let other_sig = outsider.sign(&other.sig_message(&s.canister_id));
+	assert_eq!(
+		s.canister.register_payout(other, other_sig, 0),
+		Err(Error::Authentication)
+	);
+	assert_ok!(s.canister.register_payout(req, sig, 0));
+}
+
+#[test]
+/// Tests that a non-final disputed channel can be concluded by its identifier
+/// once its dispute timed out, which starts the registered payouts.
+fn test_conclude_dispute() {
+	let mut s = test::Setup::new(false, true);
+	let channel = s.params.id();
+	assert_eq!(
+		s.canister.conclude_dispute(&channel, 0),
+		Err(Error::NotFinalized)
+	);
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.dispute(s.params.clone(), sstate, 0));
+	let (req, sig) = s.withdrawal(0);
+	assert_ok!(s.canister.register_payout(req, sig, 0));
+	let timeout = s.canister.state(&channel).unwrap().timeout;
+
+	assert_eq!(
+		s.canister.conclude_dispute(&channel, timeout - 1),
+		Err(Error::NotFinalized)
+	);
+	let payouts = s.canister.conclude_dispute(&channel, timeout).unwrap();
+	assert_eq!(payouts.len(), 1);
+	assert_eq!(payouts[0].0, s.funding(0));
+	assert_eq!(
+		s.canister.query_holdings(s.funding(1)),
+		Some(s.state.allocation[0][1].clone())
+	);
+
+	// The channel is concluded only once.
+	assert_eq!(
+		s.canister.conclude_dispute(&channel, timeout),
+		Err(Error::AlreadyConcluded)
+	);
+	assert_eq!(s.canister.settle_expired(timeout), (vec![], vec![]));
+}
+
+#[test]
+/// Tests that concluding a disputed channel replaces its scheduled settlement
+/// with an immediate one.
+fn test_settle_concluded_dispute() {
+	let mut s = test::Setup::new(false, true);
+	let channel = s.params.id();
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.dispute(s.params.clone(), sstate, 0));
+
+	s.state.version += 1;
+	s.state.finalized = true;
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+	assert_eq!(s.canister.settle_expired(0), (vec![channel], vec![]));
+	// The dispute's timeout is no longer scheduled.
+	assert_eq!(s.canister.settle_expired(u64::MAX), (vec![], vec![]));
+
+	// Payouts cannot be registered for settled channels.
+	let (req, sig) = s.withdrawal(0);
+	assert_eq!(
+		s.canister.register_payout(req, sig, 0),
+		Err(Error::AlreadyConcluded)
+	);
+}
+
+#[test]
+/// Tests that funds pulled from an allowance are credited only once per ledger
+/// block, both when the pull is repeated and when the block is also submitted
+/// as transaction notification.
+fn test_deposit_pulled_once() {
+	let mut s = test::Setup::new(false, false);
+	let funding = s.funding(0);
+	assert!(s
+		.canister
+		.deposit_pulled(funding.clone(), 10.into(), 5)
+		.unwrap()
+		.is_some());
+	// A repeated pull that the ledger deduplicated returns the same block.
+	assert!(s
+		.canister
+		.deposit_pulled(funding.clone(), 10.into(), 5)
+		.unwrap()
+		.is_none());
+	assert_eq!(s.canister.query_holdings(funding.clone()), Some(10.into()));
+
+	// The pull carries the funding's memo, but cannot be claimed again.
+	assert_eq!(
+		block_on(s.canister.process_icp_tx(funding.asset.clone(), 5)),
+		Err(Error::ReceiverError(
+			icp::ICPReceiverError::DuplicateTransaction
+		))
+	);
+	assert_eq!(
+		block_on(s.canister.process_icp_tx(funding.asset.clone(), 6)),
+		Err(Error::ReceiverError(icp::ICPReceiverError::FailedToQuery))
+	);
+	assert_eq!(s.canister.query_holdings(funding), Some(10.into()));
+}
+
+#[test]
+/// Tests that a sweep with unknown outcome stays journaled, even once it is
+/// too old to be deduplicated, is repeated with identical arguments and
+/// credited exactly once, and that rejected sweeps are dropped without
+/// crediting anything.
+fn test_sweep_unknown_outcome() {
+	let mut s = test::Setup::new(false, false);
+	let funding = s.funding(0);
+	let sweep = SweepEntry {
+		amount: 10.into(),
+		created_at_time: 5,
+	};
+	let newer = SweepEntry {
+		created_at_time: 6,
+		..sweep.clone()
+	};
+
+	assert_eq!(s.canister.begin_sweep(&funding, sweep.clone()), sweep);
+	assert_eq!(
+		s.canister
+			.finish_sweep(&funding, &sweep, Err(icp::PayoutError::Unknown))
+			.err(),
+		Some(Error::LedgerError)
+	);
+	assert_eq!(s.canister.query_holdings(funding.clone()), None);
+	// A sweep too old to be deduplicated may have been executed as well.
+	assert_eq!(
+		s.canister
+			.finish_sweep(&funding, &sweep, Err(icp::PayoutError::Expired))
+			.err(),
+		Some(Error::LedgerError)
+	);
+
+	// The next deposit repeats the journaled sweep instead of starting anew.
+	assert_eq!(s.canister.pending_sweep(&funding), Some(sweep.clone()));
+	assert_eq!(s.canister.begin_sweep(&funding, newer.clone()), sweep);
+
+	// The ledger reports the repetition as duplicate of the executed sweep,
+	// which is credited once even if both calls complete.
+	assert_ok!(s.canister.finish_sweep(&funding, &sweep, Ok(7)));
+	assert_ok!(s.canister.finish_sweep(&funding, &sweep, Ok(7)));
+	assert_eq!(s.canister.query_holdings(funding.clone()), Some(10.into()));
+	assert_eq!(s.canister.pending_sweep(&funding), None);
+
+	assert_eq!(s.canister.begin_sweep(&funding, newer.clone()), newer);
+	assert_eq!(
+		s.canister
+			.finish_sweep(&funding, &newer, Err(icp::PayoutError::Rejected))
+			.err(),
+		Some(Error::LedgerError)
+	);
+	assert_eq!(s.canister.pending_sweep(&funding), None);
+	assert_eq!(s.canister.query_holdings(funding), Some(10.into()));
+}
+
+#[test]
+/// Tests that sweeps too old to be deduplicated stay journaled until an
+/// administrator settles them: executed sweeps are credited even if the deposit
+/// address was topped up meanwhile, while sweeps that were not executed are
+/// dropped, so that the next deposit sweeps the deposit address anew.
+fn test_sweep_expired() {
+	let mut s = test::Setup::new(false, false);
+	let admin = Principal::from_slice(&[1]);
+	s.canister.config.admins.push(admin);
+	let funding = s.funding(0);
+	let sweep = SweepEntry {
+		amount: 10.into(),
+		created_at_time: 5,
+	};
+	let top_up = SweepEntry {
+		amount: 12.into(),
+		created_at_time: 9,
+	};
+
+	// The sweep was executed and the deposit address topped up afterwards.
+	s.canister.begin_sweep(&funding, sweep.clone());
+	assert_eq!(
+		s.canister
+			.finish_sweep(&funding, &sweep, Err(icp::PayoutError::Expired))
+			.err(),
+		Some(Error::LedgerError)
+	);
+	assert_eq!(s.canister.pending_sweep(&funding), Some(sweep.clone()));
+	assert_eq!(
+		s.canister
+			.settle_sweep(&Principal::anonymous(), &funding, Some(7))
+			.err(),
+		Some(Error::Authentication)
+	);
+	assert!(matches!(
+		s.canister.settle_sweep(&admin, &funding, Some(7)),
+		Ok(Some(Event::Funded { .. }))
+	));
+	assert_eq!(s.canister.pending_sweep(&funding), None);
+	assert_eq!(s.canister.query_holdings(funding.clone()), Some(10.into()));
+	// The top-up is swept by the next deposit.
+	assert_eq!(s.canister.begin_sweep(&funding, top_up.clone()), top_up);
+	assert_ok!(s.canister.finish_sweep(&funding, &top_up, Ok(8)));
+	assert_eq!(s.canister.query_holdings(funding.clone()), Some(22.into()));
+
+	// A sweep that was not executed is dropped without crediting anything.
+	s.canister.begin_sweep(&funding, sweep.clone());
+	assert!(matches!(
+		s.canister.settle_sweep(&admin, &funding, None),
+		Ok(None)
+	));
+	assert_eq!(s.canister.pending_sweep(&funding), None);
+	assert_eq!(s.canister.query_holdings(funding.clone()), Some(22.into()));
+	// Only journaled sweeps can be settled.
+	assert_eq!(
+		s.canister.settle_sweep(&admin, &funding, None).err(),
+		Some(Error::InvalidInput)
+	);
+}
+
+#[test]
+/// Tests that the canister's own sweeps are recognised by their source account
+/// and cannot be claimed via transaction notifications, while outside
+/// transfers with the sweep memo are credited.
+fn test_sweep_notification() {
+	let mut s = test::Setup::new(false, false);
+	let funding = s.funding(0);
+	let canister = icrc1::Account {
+		owner: Principal::anonymous(),
+		subaccount: None,
+	};
+	let deposit = ledger::DepositAddress::new(Principal::anonymous(), &funding);
+	let tx = |from: &icrc1::Account| icp::TransactionNotification {
+		from: Some(from.identifier()),
+		to: canister.identifier(),
+		amount: 10,
+		memo: icp::SWEEP_MEMO,
+	};
+	let sweep = SweepEntry {
+		amount: 10.into(),
+		created_at_time: 5,
+	};
+
+	// A journaled sweep is notified before its outcome is recorded.
+	s.canister.begin_sweep(&funding, sweep.clone());
+	assert_eq!(
+		s.canister
+			.record_icp_tx(&funding.asset, 7, tx(&deposit.account)),
+		Err(Error::ReceiverError(icp::ICPReceiverError::TransactionType))
+	);
+	assert_ok!(s.canister.finish_sweep(&funding, &sweep, Ok(8)));
+	// An executed sweep is notified after its outcome is recorded.
+	assert_eq!(
+		s.canister.check_icp_tx(&funding.asset, 8),
+		Err(Error::ReceiverError(
+			icp::ICPReceiverError::DuplicateTransaction
+		))
+	);
+
+	let outside = icrc1::Account {
+		owner: Principal::from_slice(&[1]),
+		subaccount: None,
+	};
+	assert_eq!(
+		s.canister.record_icp_tx(&funding.asset, 9, tx(&outside)),
+		Ok(10.into())
+	);
+}
+
+#[test]
+/// Tests that a transaction notification queried from the ledger is only
+/// credited once, even if another notification of the same transaction was
+/// processed while the ledger was queried.
+fn test_transaction_notification_once() {
+	let mut s = test::Setup::new(false, false);
+	let funding = s.funding(0);
+	let tx = icp::TransactionNotification {
+		from: None,
+		to: icrc1::Account {
+			owner: Principal::anonymous(),
+			subaccount: None,
+		}
+		.identifier(),
+		amount: 10,
+		memo: funding.memo(),
+	};
+
+	assert_ok!(s.canister.check_icp_tx(&funding.asset, 5));
+	assert_ok!(s.canister.check_icp_tx(&funding.asset, 5));
+	assert_eq!(
+		s.canister.record_icp_tx(&funding.asset, 5, tx.clone()),
+		Ok(10.into())
+	);
+	assert_eq!(
+		s.canister.record_icp_tx(&funding.asset, 5, tx),
+		Err(Error::ReceiverError(
+			icp::ICPReceiverError::DuplicateTransaction
+		))
+	);
+	assert_eq!(
+		s.canister.check_icp_tx(&funding.asset, 5),
+		Err(Error::ReceiverError(
+			icp::ICPReceiverError::DuplicateTransaction
+		))
+	);
+
+	// The registered funds are only credited in the legacy memo deposit mode.
+	assert_ok!(s.canister.deposit_icp(funding.clone(), Amount::default()));
+	let credited = cfg!(feature = "memo-deposits").then(|| 10.into());
+	assert_eq!(s.canister.query_holdings(funding), credited);
+}
+
+#[test]
+/// Tests that deposits without any funds to credit leave the state untouched.
+fn test_deposit_nothing() {
+	let mut s = test::Setup::new(false, false);
+	let funding = s.funding(0);
+	let root = s.canister.certified_root();
+	assert!(matches!(
+		s.canister.deposit_icp(funding.clone(), Amount::default()),
+		Ok(None)
+	));
+	assert_eq!(s.canister.query_holdings(funding.clone()), None);
+	assert!(!s.canister.phases.contains_key(&funding.channel));
+	assert_eq!(s.canister.certified_root(), root);
+}
+
+#[test]
+/// Tests that deposits return a funding event with the funding's new total.
+fn test_deposit_event() {
+	let mut s = test::Setup::new(false, false);
+	let funding = s.funding(0);
+	assert_ok!(s.canister.deposit(funding.clone(), 10.into()));
+
+	match s.canister.deposit_received(funding.clone(), 5.into()) {
+		Ok(Event::Funded { who, asset, total }) => {
+			assert_eq!(who, funding.participant);
+			assert_eq!(asset, funding.asset);
+			assert_eq!(total, Amount::from(15));
+		}
+		_ => panic!("expected funding event"),
+	}
+}
+
+#[test]
+/// Tests that event pages can be resumed without duplicates, even for events
+/// registered at the same time, and that sequence numbers are global.
+fn test_events_page() {
+	let mut s = test::Setup::new(false, false);
+	let ch = s.params.id();
+	let other = test::rand_hash(&mut s.prng);
+	let mut log = LocalEventRegisterer::new();
+	for _ in 0..3 {
+		log.push(1, ch.clone(), Event::Concluded);
+	}
+	assert_eq!(log.push(1, other.clone(), Event::Concluded), 3);
+	log.push(2, ch.clone(), Event::Concluded);
+
+	let page = log.events_page(&ch, 0, 2);
+	assert_eq!(
+		page.events.iter().map(|r| r.seq).collect::<Vec<_>>(),
+		[0, 1]
+	);
+	assert_eq!(page.next_cursor, 2);
+	let page = log.events_page(&ch, page.next_cursor, 2);
+	assert_eq!(
+		page.events.iter().map(|r| r.seq).collect::<Vec<_>>(),
+		[2, 4]
+	);
+	assert_eq!(page.events[1].time, 2);
+	let page = log.events_page(&ch, page.next_cursor, 2);
+	assert!(page.events.is_empty());
+	assert_eq!(page.next_cursor, 5);
+
+	// Pages are limited in size.
+	for _ in 0..events::MAX_PAGE_SIZE {
+		log.push(3, other.clone(), Event::Concluded);
+	}
+	let page = log.events_page(&other, 0, u64::MAX);
+	assert_eq!(page.events.len() as u64, events::MAX_PAGE_SIZE);
+}
+
+#[test]
+/// Tests that only old events of concluded channels are garbage collected and
+/// that collections happen at most once per interval.
+fn test_events_gc() {
+	let mut s = test::Setup::new(false, false);
+	let open = s.params.id();
+	let concluded = test::rand_hash(&mut s.prng);
+	let funded = Event::Funded {
+		who: s.parts[0].clone(),
+		asset: s.params.assets[0].clone(),
+		total: 1.into(),
+	};
+	let mut log = LocalEventRegisterer::new();
+	log.push(1, open.clone(), funded.clone());
+	log.push(1, open.clone(), funded);
+	log.push(1, concluded.clone(), Event::Concluded);
+	log.push(5, concluded.clone(), Event::Concluded);
+	assert_eq!(
+		log.metrics(),
+		EventMetrics {
+			retained: 4,
+			oldest: Some(1)
+		}
+	);
+
+	log.collect_garbage(10, 7);
+	assert_eq!(log.events_after(&open, 0).len(), 2);
+	assert_eq!(log.events_after(&concluded, 0).len(), 1);
+	assert_eq!(log.metrics().retained, 3);
+
+	// The next collection is not due yet.
+	log.collect_garbage(20, 7);
+	assert_eq!(log.events_after(&concluded, 0).len(), 1);
+	log.collect_garbage(10 + events::GC_INTERVAL, 7);
+	assert_eq!(log.events_after(&concluded, 0).len(), 0);
+}
+
+#[test]
+/// Tests the global event feed and its participant filter, including after
+/// garbage collection.
+fn test_all_events() {
+	let mut s = test::Setup::new(false, false);
+	let ch = s.params.id();
+	let other = test::rand_hash(&mut s.prng);
+	let funded = |who: &L2Account| Event::Funded {
+		who: who.clone(),
+		asset: Asset::default(),
+		total: 1.into(),
+	};
+	let mut log = LocalEventRegisterer::new();
+	log.push(1, ch.clone(), funded(&s.parts[0]));
+	log.push(1, other.clone(), funded(&s.parts[1]));
+	log.push(2, ch.clone(), Event::Concluded);
+	log.push(3, other.clone(), Event::Concluded);
+
+	let page = log.all_events(1, 2);
+	assert_eq!(page.events.len(), 2);
+	assert!(page.events[0].channel == other && page.events[1].channel == ch);
+	assert_eq!(page.next_cursor, 3);
+
+	let page = log.participant_events(&s.parts[0], 0, 10);
+	assert_eq!(
+		page.events.iter().map(|r| r.seq).collect::<Vec<_>>(),
+		[0, 2]
+	);
+	assert_eq!(page.next_cursor, 4);
+
+	log.gc(2);
+	let page = log.all_events(0, 10);
+	assert_eq!(
+		page.events.iter().map(|r| r.seq).collect::<Vec<_>>(),
+		[2, 3]
+	);
+
+	// Channels without remaining events are forgotten, including their
+	// participants and conclusion.
+	log.gc(4);
+	log.push(5, ch.clone(), funded(&s.parts[1]));
+	assert!(log.participant_events(&s.parts[0], 0, 10).events.is_empty());
+	log.gc(6);
+	assert_eq!(log.events_after(&ch, 0).len(), 1);
+}
+
+#[test]
+/// Tests that only administrators can replace the canister's configuration.
+fn test_update_config() {
+	let admin = Principal::from_slice(&[1]);
+	let config = Config {
+		admins: vec![admin],
+		..Default::default()
+	};
+	let mut canister = CanisterState::new(
+		icp::MockTXQuerier::default(),
+		Principal::anonymous(),
+		config,
+	);
+	let asset = Asset {
+		ledger: Principal::from_slice(&[2]),
+		..Default::default()
+	};
+	let mut update = canister.config().clone();
+	update.assets.push(AssetConfig {
+		asset: asset.clone(),
+		fee: 1.into(),
+	});
+
+	assert_eq!(
+		canister.update_config(&Principal::anonymous(), update.clone()),
+		Err(Error::Authentication)
+	);
+	assert!(!canister.config().supports(&asset));
+	assert_ok!(canister.update_config(&admin, update));
+	assert!(canister.config().supports(&asset));
+}
+
+#[test]
+/// Tests that the events of a disputed channel are attributed to its
+/// participants, even though none of them deposited into it.
+fn test_members_from_params() {
+	let mut s = test::Setup::new(false, true);
+	let ch = s.params.id();
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.dispute(s.params.clone(), sstate, 0));
+	let members = s.canister.params(&ch).unwrap().participants.clone();
+	assert_eq!(members, s.parts);
+	let event = Event::Disputed(s.canister.state(&ch).unwrap());
+
+	let mut log = LocalEventRegisterer::new();
+	log.add_members(&ch, &members);
+	log.push(1, ch.clone(), event.clone());
+	for who in s.parts.iter() {
+		assert_eq!(log.participant_events(who, 0, 10).events.len(), 1);
+	}
+
+	let mut subs = subscriptions::Subscriptions::default();
+	let canister = Principal::anonymous();
+	let topic = subscriptions::Topic::Participant(s.parts[1].clone());
+	let id = subs
+		.subscribe(canister, topic, "a".into(), subscriptions::SUBSCRIPTION_FEE)
+		.unwrap();
+	subs.publish(1, ch.clone(), event, &log.members(&ch));
+	let due = subs.take_due(1);
+	assert_eq!(due.len(), 1);
+	assert_eq!(due[0].1.subscription, id);
+
+	// Only the participants of channels without stored events are forgotten.
+	let forwarded = test::rand_hash(&mut s.prng);
+	log.add_members(&forwarded, &members);
+	log.forget_members(&forwarded);
+	log.forget_members(&ch);
+	assert!(log.members(&forwarded).is_empty());
+	assert_eq!(log.members(&ch), members);
+}
+
+#[test]
+/// Tests that events are delivered to the subscriptions of their channel and
+/// participants only.
+fn test_subscriptions_publish() {
+	let mut s = test::Setup::new(false, false);
+	let ch = s.params.id();
+	let other = test::rand_hash(&mut s.prng);
+	let mut subs = subscriptions::Subscriptions::default();
+	let canister = Principal::anonymous();
+	let by_channel = subs
+		.subscribe(
+			canister,
+			subscriptions::Topic::Channel(ch.clone()),
+			"a".into(),
+			subscriptions::SUBSCRIPTION_FEE,
+		)
+		.unwrap();
+	let by_part = subs
+		.subscribe(
+			canister,
+			subscriptions::Topic::Participant(s.parts[0].clone()),
+			"b".into(),
+			subscriptions::SUBSCRIPTION_FEE,
+		)
+		.unwrap();
+
+	// The participant is not yet known to be part of the channel.
+	subs.publish(1, ch.clone(), Event::Concluded, &[]);
+	subs.publish(
+		2,
+		ch.clone(),
+		Event::Funded {
+			who: s.parts[0].clone(),
+			asset: Asset::default(),
+			total: 1.into(),
+		},
+		&s.parts[..1],
+	);
+	subs.publish(3, other, Event::Concluded, &[]);
+
+	let mut delivered = vec![];
+	for (_, d) in subs.take_due(3) {
+		delivered.push((d.subscription, d.time));
+		subs.delivered(d, true, 3);
+	}
+	assert_eq!(delivered, [(by_channel, 1), (by_channel, 2), (by_part, 2)]);
+
+	// Nothing is delivered twice.
+	assert!(subs.take_due(3).is_empty());
+
+	// Deliveries are capped per heartbeat.
+	for _ in 0..=subscriptions::MAX_DELIVERIES {
+		subs.publish(4, ch.clone(), Event::Concluded, &[]);
+	}
+	assert_eq!(subs.take_due(4).len(), subscriptions::MAX_DELIVERIES);
+	assert_eq!(subs.take_due(4).len(), 1);
+	assert!(subs.take_due(4).is_empty());
+}
+
+#[test]
+/// Tests that the deliveries queued per subscription are capped, dropping
+/// those of the oldest events.
+fn test_subscriptions_queue_cap() {
+	let s = test::Setup::new(false, false);
+	let ch = s.params.id();
+	let mut subs = subscriptions::Subscriptions::default();
+	let id = subs
+		.subscribe(
+			Principal::anonymous(),
+			subscriptions::Topic::Channel(ch.clone()),
+			"a".into(),
+			subscriptions::SUBSCRIPTION_FEE,
+		)
+		.unwrap();
+	let max = subscriptions::MAX_QUEUED as Timestamp;
+	for time in 0..=max {
+		subs.publish(time, ch.clone(), Event::Concluded, &[]);
+	}
+
+	// A failed delivery of an older event is dropped when retried.
+	let (_, oldest) = subs.take_due(1).pop().unwrap();
+	assert_eq!(oldest.time, 1);
+	subs.publish(max + 1, ch, Event::Concluded, &[]);
+	subs.delivered(oldest, false, 0);
+	assert_eq!(subs.subscription(id).unwrap().failures, 1);
+
+	let mut times = vec![];
+	loop {
+		let due = subs.take_due(Timestamp::MAX);
+		if due.is_empty() {
+			break;
+		}
+		times.extend(due.into_iter().map(|(_, d)| d.time));
+	}
+	assert_eq!(times, (2..=max + 1).collect::<Vec<_>>());
+}
+
+#[test]
+/// Tests that failed deliveries are retried with backoff and that subscribers
+/// are unsubscribed after repeated failures.
+fn test_subscriptions_retry() {
+	let s = test::Setup::new(false, false);
+	let ch = s.params.id();
+	let mut subs = subscriptions::Subscriptions::default();
+	let canister = Principal::anonymous();
+	let id = subs
+		.subscribe(
+			canister,
+			subscriptions::Topic::Channel(ch.clone()),
+			"a".into(),
+			subscriptions::SUBSCRIPTION_FEE,
+		)
+		.unwrap();
+	subs.publish(0, ch, Event::Concluded, &[]);
+
+	let mut attempts = 0;
+	for (_, d) in subs.take_due(0) {
+		attempts += 1;
+		subs.delivered(d, false, 0);
+	}
+	assert_eq!(subs.subscription(id).unwrap().failures, 1);
+	// The retry is not due yet.
+	assert!(subs.take_due(subscriptions::RETRY_DELAY - 1).is_empty());
+
+	let mut now = 0;
+	for _ in 1..subscriptions::MAX_FAILURES {
+		now += subscriptions::RETRY_DELAY << 16;
+		for (_, d) in subs.take_due(now) {
+			attempts += 1;
+			subs.delivered(d, false, now);
+		}
+	}
+	assert_eq!(attempts, subscriptions::MAX_FAILURES);
+	assert!(subs.subscription(id).is_none());
+	assert_eq!(subs.unsubscribe(canister, id), Err(Error::InvalidInput));
+}
+
+#[test]
+/// Tests that subscriptions require the subscription fee and that the number
+/// of subscriptions is limited per canister.
+fn test_subscriptions_limits() {
+	let mut subs = subscriptions::Subscriptions::default();
+	let topic = subscriptions::Topic::Channel(Default::default());
+	let fee = subscriptions::SUBSCRIPTION_FEE;
+	let canister = |i: usize| Principal::from_slice(&i.to_be_bytes());
+	assert_eq!(
+		subs.subscribe(canister(0), topic.clone(), "a".into(), fee - 1),
+		Err(Error::InsufficientCycles)
+	);
+
+	for _ in 0..subscriptions::MAX_SUBSCRIPTIONS {
+		assert_ok!(subs.subscribe(canister(0), topic.clone(), "a".into(), fee));
+	}
+	assert_eq!(
+		subs.subscribe(canister(0), topic.clone(), "a".into(), fee),
+		Err(Error::InvalidInput)
+	);
+	// Other canisters are not affected.
+	assert_ok!(subs.subscribe(canister(1), topic, "a".into(), fee));
+}
+
+#[test]
+/// Tests that garbage collecting events updates the certified latest events,
+/// so that certified event queries stay verifiable.
+fn test_events_gc_certified() {
+	let mut s = test::Setup::new(false, false);
+	let partial = test::rand_hash(&mut s.prng);
+	let collected = test::rand_hash(&mut s.prng);
+	let mut log = LocalEventRegisterer::new();
+	log.push(1, partial.clone(), Event::Concluded);
+	log.push(5, partial.clone(), Event::Concluded);
+	log.push(1, collected.clone(), Event::Concluded);
+	for head in log.heads() {
+		s.canister.certify_event(head);
+	}
+
+	let channels = log.gc(3);
+	assert_eq!(channels.len(), 2);
+	s.canister.certify_collected_events(&log, &channels);
+	let root = s.canister.certified_root();
+
+	let key = certification::events_key(&partial);
+	let head = log.head(&partial);
+	assert_eq!(head.as_ref().map(|r| r.time), Some(5));
+	let witness = s.canister.witness(&key);
+	assert!(certification::verify(&root, &witness, &key, head.as_ref()));
+
+	let key = certification::events_key(&collected);
+	assert!(log.head(&collected).is_none());
+	let witness = s.canister.witness(&key);
+	assert!(certification::verify::<EventRecord>(
+		&root, &witness, &key, None
+	));
+}
+
+#[test]
+/// Tests that witnesses of the certified data tree prove the certified
+/// holdings and states as well as their absence, and that changes to the
+/// certified values invalidate previous witnesses.
+fn test_certified_witness() {
+	let mut s = test::Setup::new(true, false);
+	let amount: Amount = 10.into();
+	s.state.allocation[0] = vec![amount.clone(), Amount::default()];
+	let funding = s.funding(0);
+	let key = certification::holdings_key(&funding);
+	let absent = s.canister.witness(&key);
+	assert_eq!(absent.lookup(&key), certification::Lookup::Absent);
+	let root = s.canister.certified_root();
+	assert!(certification::verify::<Amount>(&root, &absent, &key, None));
+
+	assert_ok!(s.canister.deposit(funding.clone(), amount.clone()));
+	let root = s.canister.certified_root();
+	let witness = s.canister.witness(&key);
+	assert!(certification::verify(&root, &witness, &key, Some(&amount)));
+	assert!(!certification::verify(
+		&root,
+		&witness,
+		&key,
+		Some(&Amount::from(11u64))
+	));
+	assert!(!certification::verify::<Amount>(
+		&root, &witness, &key, None
+	));
+	assert!(!certification::verify::<Amount>(&root, &absent, &key, None));
+
+	// Concluding the channel certifies its state, which changes the root.
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+	let root = s.canister.certified_root();
+	assert!(!certification::verify(&root, &witness, &key, Some(&amount)));
+	let id = s.state.channel.clone();
+	let state_key = certification::state_key(&id);
+	let witness = s.canister.witness(&state_key);
+	assert!(certification::verify(
+		&root,
+		&witness,
+		&state_key,
+		s.canister.state(&id).as_ref()
+	));
+}
+
+#[test]
+/// Tests that states and withdrawal requests signed for another canister are
+/// rejected.
+fn test_signatures_bound_to_canister() {
+	let mut s = test::Setup::new(true, true);
+	s.canister_id = test::default_account();
+	let sstate = s.sign_state();
+	assert_eq!(
+		s.canister.conclude(s.params.clone(), sstate, 0),
+		Err(Error::Authentication)
+	);
+
+	s.canister_id = s.canister.principal().clone();
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+
+	s.canister_id = test::default_account();
+	let (req, sig) = s.withdrawal(0);
+	assert_eq!(s.canister.withdraw(req, sig, 0), Err(Error::Authentication));
+}
+
+#[test]
+/// Tests that expired withdrawal requests are rejected, and that a request
+/// rejected by the ledger cannot be replayed once its funds are credited back.
+fn test_withdrawal_replay_protection() {
+	let mut s = test::Setup::new(true, true);
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+	let funding = s.funding(0);
+
+	let (mut req, _) = s.withdrawal(0);
+	req.valid_until = Some(5);
+	let sig = s.sign_withdrawal(&req, 0);
+	assert_eq!(
+		s.canister.begin_withdrawal(req.clone(), sig.clone(), 6),
+		Err(Error::WithdrawalExpired)
+	);
+
+	assert_ok!(s.canister.begin_withdrawal(req.clone(), sig.clone(), 5));
+	assert_eq!(
+		s.canister
+			.finish_withdrawal(&funding, Err(icp::PayoutError::Rejected)),
+		Err(Error::LedgerError)
+	);
+	assert_eq!(
+		s.canister.begin_withdrawal(req, sig, 5),
+		Err(Error::WithdrawalNonceUsed)
+	);
+
+	let (req, sig) = s.withdrawal_nonce(0, 1);
+	assert_ok!(s.canister.begin_withdrawal(req, sig, 5));
+}
+
+#[test]
+/// Tests that layer-2 accounts of both signature schemes survive a candid
+/// roundtrip and only accept their own signatures.
+fn test_l2_account_schemes() {
+	let mut s = test::Setup::new(false, false);
+	let signers = vec![
+		test::rand_signer(&mut s.prng, test::Scheme::Ed25519),
+		test::rand_signer(&mut s.prng, test::Scheme::Secp256k1),
+	];
+	for (i, signer) in signers.iter().enumerate() {
+		let account = signer.account();
+		let encoded = candid::Encode!(&account).unwrap();
+		assert_eq!(candid::Decode!(&encoded, L2Account).unwrap(), account);
+
+		let sig = signer.sign(b"message");
+		assert_ok!(account.verify(b"message", &sig));
+		assert_eq!(
+			account.verify(b"other message", &sig),
+			Err(Error::Authentication)
+		);
+		let other = signers[1 - i].account();
+		assert_eq!(other.verify(b"message", &sig), Err(Error::Authentication));
+	}
+}
+
+#[test]
+/// Tests that secp256k1 signatures are also accepted with the recovery id in
+/// Ethereum's 27/28 form, but not with other recovery ids.
+fn test_secp256k1_recovery_id() {
+	let mut s = test::Setup::new(false, false);
+	let signer = test::rand_signer(&mut s.prng, test::Scheme::Secp256k1);
+	let account = signer.account();
+	let mut sig = signer.sign(b"message");
+	assert!(sig.0[64] < 2);
+
+	sig.0[64] += 27;
+	assert_ok!(account.verify(b"message", &sig));
+	sig.0[64] += 2;
+	assert_eq!(account.verify(b"message", &sig), Err(Error::Authentication));
+}
+
+#[test]
+/// Tests that the principal bound to a participant can withdraw the
+/// participant's funds without a signature, and that other callers cannot.
+fn test_withdraw_as_caller() {
+	let mut s = test::Setup::new(true, false);
+	let owner = test::default_account();
+	s.params.principals = vec![Some(owner.clone()), None];
+	s.state.channel = s.params.id();
+	for i in 0..s.parts.len() {
+		let amount = s.state.allocation[0][i].clone();
+		assert_ok!(s.canister.deposit(s.funding(i), amount));
+	}
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+
+	assert_eq!(
+		s.canister
+			.begin_withdrawal_as(s.funding(0), Principal::anonymous(), 0),
+		Err(Error::Authentication)
+	);
+	assert_eq!(
+		s.canister
+			.begin_withdrawal_as(s.funding(1), owner.clone(), 0),
+		Err(Error::Authentication)
+	);
+	let entry = s
+		.canister
+		.begin_withdrawal_as(s.funding(0), owner.clone(), 0)
+		.unwrap();
+	assert_eq!(entry.receiver, owner);
+	assert_eq!(s.canister.query_holdings(s.funding(0)), None);
+}
+
+#[test]
+/// Tests that withdrawals to the caller fail once the funding's nonces are
+/// used up instead of overflowing.
+fn test_withdraw_as_caller_nonce_exhausted() {
+	let mut s = test::Setup::new(true, false);
+	let owner = test::default_account();
+	s.params.principals = vec![Some(owner.clone()), None];
+	s.state.channel = s.params.id();
+	for i in 0..s.parts.len() {
+		let amount = s.state.allocation[0][i].clone();
+		assert_ok!(s.canister.deposit(s.funding(i), amount));
+	}
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+
+	let funding = s.funding(0);
+	let (mut req, _) = s.withdrawal(0);
+	req.nonce = u64::MAX;
+	let sig = s.sign_withdrawal(&req, 0);
+	assert_ok!(s.canister.begin_withdrawal(req, sig, 0));
+	assert_eq!(
+		s.canister
+			.finish_withdrawal(&funding, Err(icp::PayoutError::Rejected)),
+		Err(Error::LedgerError)
+	);
+	assert_eq!(
+		s.canister.begin_withdrawal_as(funding, owner, 0),
+		Err(Error::WithdrawalNonceUsed)
+	);
+}
+
+/// Creates a setup for a funded tic-tac-toe channel in its initial state, in
+/// which the first participant moves first.
+fn tictactoe_setup() -> test::Setup {
+	let mut s = test::Setup::new(false, false);
+	s.params.app = Some(tictactoe::APP_ID.into());
+	s.state.channel = s.params.id();
+	s.state.version = 0;
+	s.state.data = tictactoe::Board::new(0).encode();
+	for i in 0..s.parts.len() {
+		let amount = s.state.allocation[0][i].clone();
+		assert_ok!(s.canister.deposit(s.funding(i), amount));
+	}
+	s
+}
+
+/// Marks a field for the participant whose turn it is. Returns the resulting
+/// state and the participant's signature on it.
+fn tictactoe_move(s: &test::Setup, old: &State, field: usize) -> (State, L2Signature) {
+	let mut board = tictactoe::Board::decode(&old.data).unwrap();
+	let actor = board.next_actor as usize;
+	board.mark(field);
+	let mut new = old.clone();
+	new.version += 1;
+	new.data = board.encode();
+	new.finalized = board.over();
+	new.allocation = tictactoe::outcome(&old.allocation, board.winner());
+	let sig = s.sign_state_by(&new, actor);
+	(new, sig)
+}
+
+#[test]
+/// Tests that app channels only accept valid initial states.
+fn test_app_valid_init() {
+	let mut s = tictactoe_setup();
+	let mut board = tictactoe::Board::new(0);
+	board.mark(4);
+	s.state.data = board.encode();
+	let sstate = s.sign_state();
+	assert_eq!(
+		s.canister.dispute(s.params.clone(), sstate, 0),
+		Err(Error::InvalidInput)
+	);
+
+	s.params.app = Some("chess".into());
+	s.state.channel = s.params.id();
+	let sstate = s.sign_state();
+	assert_eq!(
+		s.canister.dispute(s.params.clone(), sstate, 0),
+		Err(Error::UnsupportedApp)
+	);
+}
+
+#[test]
+/// Tests that an app channel can be concluded right after funding via a final
+/// initial state, which the app's rules do not apply to.
+fn test_app_finalized_init() {
+	let mut s = tictactoe_setup();
+	let id = s.state.channel.clone();
+	s.state.finalized = true;
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.conclude(s.params.clone(), sstate, 0));
+	assert!(s.canister.state(&id).unwrap().settled(0));
+}
+
+#[test]
+/// Tests that a disputed tic-tac-toe channel can be advanced by the player
+/// whose turn it is until the game is won, which finalizes the channel and
+/// pays the winner.
+fn test_tictactoe_progress() {
+	let mut s = tictactoe_setup();
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.dispute(s.params.clone(), sstate, 0));
+	let id = s.state.channel.clone();
+	let mut old = s.state.clone();
+	let now = s.params.challenge_duration;
+
+	// The second participant may not move first.
+	let (new, _) = tictactoe_move(&s, &old, 4);
+	let sig = s.sign_state_by(&new, 1);
+	assert_eq!(
+		s.canister
+			.progress(s.params.clone(), old.clone(), new, sig, now),
+		Err(Error::InvalidTransition)
+	);
+
+	for field in [0, 3, 1, 4] {
+		let (new, sig) = tictactoe_move(&s, &old, field);
+		assert_ok!(s
+			.canister
+			.progress(s.params.clone(), old.clone(), new.clone(), sig, now));
+		let registered = s.canister.state(&id).unwrap();
+		assert!(registered.state == new);
+		assert_eq!(s.canister.phase(&id), ChannelPhase::ForceExec);
+		assert_eq!(registered.timeout, now + s.params.challenge_duration);
+		old = new;
+	}
+
+	// Only the registered state can be advanced.
+	let (new, sig) = tictactoe_move(&s, &s.state, 4);
+	assert_eq!(
+		s.canister
+			.progress(s.params.clone(), s.state.clone(), new, sig, now),
+		Err(Error::OutdatedState)
+	);
+	// Marked fields cannot be marked again.
+	let (new, sig) = tictactoe_move(&s, &old, 0);
+	assert_eq!(
+		s.canister
+			.progress(s.params.clone(), old.clone(), new, sig, now),
+		Err(Error::InvalidTransition)
+	);
+
+	// The first participant completes the top row and wins.
+	let (new, sig) = tictactoe_move(&s, &old, 2);
+	assert_ok!(s
+		.canister
+		.progress(s.params.clone(), old.clone(), new.clone(), sig.clone(), now));
+	assert!(s.canister.state(&id).unwrap().settled(0));
+	assert_eq!(
+		s.canister.query_holdings(s.funding(0)),
+		Some(s.state.total()[0].clone())
+	);
+	assert_eq!(
+		s.canister.query_holdings(s.funding(1)),
+		Some(Amount::default())
+	);
+	assert_eq!(
+		s.canister.progress(s.params.clone(), old, new, sig, now),
+		Err(Error::AlreadyConcluded)
+	);
+}
+
+#[test]
+/// Tests that a disputed app channel can only be advanced once its challenge
+/// duration elapsed, and only settles if it is not advanced for another
+/// challenge duration.
+fn test_progress_challenge_period() {
+	let mut s = tictactoe_setup();
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.dispute(s.params.clone(), sstate, 0));
+	let id = s.state.channel.clone();
+	let d = s.params.challenge_duration;
+
+	let (new, sig) = tictactoe_move(&s, &s.state, 0);
+	assert_eq!(
+		s.canister
+			.progress(s.params.clone(), s.state.clone(), new, sig, d - 1),
+		Err(Error::ChallengePending)
+	);
+	assert_eq!(
+		s.canister.channel_status(&id, d).phase,
+		ChannelPhase::Disputed
+	);
+	assert_eq!(
+		s.canister.channel_status(&id, 2 * d).phase,
+		ChannelPhase::Concluded
+	);
+
+	// Each move restarts the challenge duration.
+	let (new, sig) = tictactoe_move(&s, &s.state, 0);
+	assert_ok!(s
+		.canister
+		.progress(s.params.clone(), s.state.clone(), new.clone(), sig, d));
+	assert_eq!(s.canister.state(&id).unwrap().timeout, 2 * d);
+	let (newer, sig) = tictactoe_move(&s, &new, 3);
+	assert_eq!(
+		s.canister
+			.progress(s.params.clone(), new, newer, sig, 2 * d),
+		Err(Error::AlreadyConcluded)
+	);
+}
+
+/// Converts a list of numbers into a single-asset allocation.
+fn amounts(balances: &[u64]) -> Vec<Vec<Amount>> {
+	vec![balances.iter().map(|&b| Amount::from(b)).collect()]
+}
+
+/// Deposits the setup's allocation plus the given amounts per participant.
+fn deposit_with_locked(s: &mut test::Setup, locked: &[u64]) {
+	for (i, &extra) in locked.iter().enumerate() {
+		let amount = s.state.allocation[0][i].clone() + Amount::from(extra);
+		assert_ok!(s.canister.deposit(s.funding(i), amount));
+	}
+}
+
+#[test]
+/// Tests that concluding a channel requires the final states of all
+/// sub-channels, recursively, and credits their outcomes to the channel's
+/// participants.
+fn test_conclude_with_subchannels() {
+	let mut s = test::Setup::new(true, false);
+	let (subsub, subsub_alloc) = s.subchannel(amounts(&[1, 2]), vec![]);
+	let (sub, sub_alloc) = s.subchannel(amounts(&[3, 4]), vec![subsub_alloc]);
+	s.state.locked = vec![sub_alloc];
+	deposit_with_locked(&mut s, &[4, 6]);
+	let params = s.params.clone();
+
+	assert_eq!(
+		s.canister.conclude(params.clone(), s.sign_state(), 0),
+		Err(Error::SubChannelNotConcluded)
+	);
+	assert_eq!(
+		s.canister
+			.conclude_with_subchannels(params.clone(), s.sign_state(), vec![sub.clone()], 0),
+		Err(Error::SubChannelNotConcluded)
+	);
+	let mut invalid = sub.clone();
+	invalid.state.sigs.swap(0, 1);
+	assert_eq!(
+		s.canister.conclude_with_subchannels(
+			params.clone(),
+			s.sign_state(),
+			vec![invalid, subsub.clone()],
+			0
+		),
+		Err(Error::Authentication)
+	);
+	assert!(s.canister.state(&s.state.channel).is_none());
+
+	assert_ok!(s.canister.conclude_with_subchannels(
+		params.clone(),
+		s.sign_state(),
+		vec![sub, subsub],
+		0
+	));
+	for (i, released) in [4u64, 6].iter().enumerate() {
+		assert_eq!(
+			s.canister.query_holdings(s.funding(i)),
+			Some(s.state.allocation[0][i].clone() + Amount::from(*released))
+		);
+	}
+	assert!(s
+		.canister
+		.state(&s.state.channel)
+		.unwrap()
+		.state
+		.locked
+		.is_empty());
+}
+
+#[test]
+/// Tests that a virtual channel with a participant outside its parent channel
+/// is released according to its index map, which credits that participant's
+/// outcome to the intermediary, and that invalid index maps are rejected.
+fn test_conclude_with_virtual_channel() {
+	let mut s = test::Setup::new(true, false);
+	// The virtual channel connects the first participant with a third party
+	// via the second participant.
+	let third = test::rand_signer(&mut s.prng, test::Scheme::Ed25519);
+	let params = Params {
+		nonce: test::rand_hash(&mut s.prng),
+		participants: vec![s.parts[0].clone(), third.account()],
+		..s.params.clone()
+	};
+	let state = State {
+		channel: params.id(),
+		version: 1,
+		allocation: amounts(&[3, 4]),
+		finalized: true,
+		data: vec![],
+		locked: vec![],
+	};
+	let sigs = vec![
+		s.sign_state_by(&state, 0),
+		third.sign(&state.sig_message(&s.canister_id)),
+	];
+	let virtual_channel = SubChannel {
+		params,
+		state: FullySignedState {
+			state: state.clone(),
+			sigs,
+		},
+	};
+	let alloc = SubAlloc {
+		id: state.channel.clone(),
+		balances: state.total(),
+		index_map: vec![0, 1],
+	};
+	deposit_with_locked(&mut s, &[3, 4]);
+	let params = s.params.clone();
+
+	for index_map in [vec![0, 2], vec![0]] {
+		s.state.locked = vec![SubAlloc {
+			index_map,
+			..alloc.clone()
+		}];
+		assert_eq!(
+			s.canister.conclude_with_subchannels(
+				params.clone(),
+				s.sign_state(),
+				vec![virtual_channel.clone()],
+				0
+			),
+			Err(Error::InvalidInput)
+		);
+	}
+
+	s.state.locked = vec![alloc];
+	assert_ok!(s.canister.conclude_with_subchannels(
+		params,
+		s.sign_state(),
+		vec![virtual_channel],
+		0
+	));
+	for (i, released) in [3u64, 4].iter().enumerate() {
+		assert_eq!(
+			s.canister.query_holdings(s.funding(i)),
+			Some(s.state.allocation[0][i].clone() + Amount::from(*released))
+		);
+	}
+}
+
+#[test]
+/// Tests that the funds a disputed channel locks in sub-channels are released
+/// after the dispute timed out, in addition to the participants' balances.
+fn test_release_subchannels_after_dispute() {
+	let mut s = test::Setup::new(false, false);
+	let (sub, sub_alloc) = s.subchannel(amounts(&[3, 4]), vec![]);
+	s.state.locked = vec![sub_alloc];
+	deposit_with_locked(&mut s, &[3, 4]);
+	let params = s.params.clone();
+	assert_ok!(s.canister.dispute(params.clone(), s.sign_state(), 0));
+
+	// The sub-channel can only be released once the dispute timed out.
+	let now = params.challenge_duration;
+	assert_eq!(
+		s.canister
+			.conclude_with_subchannels(params.clone(), s.sign_state(), vec![sub.clone()], 0),
+		Err(Error::NotFinalized)
+	);
+
+	// The participants' own balances are withdrawable before the release.
+	let (req, sig) = s.withdrawal(0);
+	assert_eq!(
+		s.canister.withdraw(req, sig, now),
+		Ok(s.state.allocation[0][0].clone())
+	);
+
+	assert_ok!(s.canister.conclude_with_subchannels(
+		params.clone(),
+		s.sign_state(),
+		vec![sub.clone()],
+		now
+	));
+	assert_eq!(
+		s.canister.query_holdings(s.funding(0)),
+		Some(Amount::from(3u64))
+	);
+	assert_eq!(
+		s.canister.query_holdings(s.funding(1)),
+		Some(s.state.allocation[0][1].clone() + Amount::from(4u64))
+	);
+	assert_eq!(
+		s.canister
+			.conclude_with_subchannels(params, s.sign_state(), vec![sub], now),
+		Err(Error::AlreadyConcluded)
+	);
+}
+
+#[test]
+/// Tests that the funds locked in a sub-channel are released even if a
+/// participant stops cooperating: the sub-channel is disputed against the
+/// funds its parent locks for it, and its registered state is used once its
+/// dispute timed out.
+fn test_release_disputed_subchannel() {
+	let mut s = test::Setup::new(false, false);
+	let (mut sub, sub_alloc) = s.subchannel(amounts(&[3, 4]), vec![]);
+	sub.state.state.finalized = false;
+	sub.state.sigs = (0..s.parts.len())
+		.map(|i| s.sign_state_by(&sub.state.state, i))
+		.collect();
+	s.state.locked = vec![sub_alloc];
+	deposit_with_locked(&mut s, &[3, 4]);
+	let params = s.params.clone();
+	let d = params.challenge_duration;
+	assert_ok!(s.canister.dispute(params.clone(), s.sign_state(), 0));
+
+	// The sub-channel is funded by its parent's lock, not by deposits.
+	assert_ok!(s.canister.dispute(sub.params.clone(), sub.state.clone(), d));
+	assert_eq!(
+		s.canister
+			.conclude_with_subchannels(params.clone(), s.sign_state(), vec![], d),
+		Err(Error::SubChannelNotConcluded)
+	);
+
+	let now = 2 * d;
+	assert_ok!(s
+		.canister
+		.conclude_with_subchannels(params, s.sign_state(), vec![], now));
+	assert_eq!(
+		s.canister.query_holdings(s.funding(0)),
+		Some(s.state.allocation[0][0].clone() + Amount::from(3u64))
+	);
+	assert_eq!(
+		s.canister.query_holdings(s.funding(1)),
+		Some(s.state.allocation[0][1].clone() + Amount::from(4u64))
+	);
+}
+
+#[test]
+/// Tests that an unfunded initial state cannot lock funds in a sub-channel,
+/// which would allow releasing funds that were never deposited.
+fn test_unfunded_subchannel_lock() {
+	let mut s = test::Setup::new(false, false);
+	let (sub, sub_alloc) = s.subchannel(amounts(&[1000, 0]), vec![]);
+	s.state.version = 0;
+	s.state.locked = vec![sub_alloc];
+	let params = s.params.clone();
+	let d = params.challenge_duration;
+	assert_eq!(
+		s.canister.dispute(params.clone(), s.sign_state(), 0),
+		Err(Error::InsufficientFunding)
+	);
+	assert!(s
+		.canister
+		.conclude_with_subchannels(params, s.sign_state(), vec![sub], 2 * d)
+		.is_err());
+	assert_eq!(s.canister.query_holdings(s.funding(0)), None);
+}
+
+#[test]
+/// Tests that only legal moves between lifecycle phases are allowed, and that
+/// illegal moves fail with an error describing why.
+fn test_channel_phase_transitions() {
+	use ChannelPhase as P;
+	assert_eq!(P::Funding.transition(P::Funded), Ok(P::Funded));
+	assert_eq!(P::Funding.transition(P::Disputed), Ok(P::Disputed));
+	assert_eq!(P::Disputed.transition(P::Disputed), Ok(P::Disputed));
+	assert_eq!(P::Disputed.transition(P::ForceExec), Ok(P::ForceExec));
+	assert_eq!(P::ForceExec.transition(P::Disputed), Ok(P::Disputed));
+	assert_eq!(P::Funded.transition(P::Concluded), Ok(P::Concluded));
+	assert_eq!(P::Concluded.transition(P::Withdrawn), Ok(P::Withdrawn));
+
+	assert_eq!(P::Funded.transition(P::ForceExec), Err(Error::NotDisputed));
+	assert_eq!(
+		P::Funding.transition(P::Concluded),
+		Err(Error::InsufficientFunding)
+	);
+	assert_eq!(
+		P::Disputed.transition(P::Withdrawn),
+		Err(Error::NotFinalized)
+	);
+	assert_eq!(
+		P::Concluded.transition(P::Disputed),
+		Err(Error::AlreadyConcluded)
+	);
+	assert_eq!(
+		P::Withdrawn.transition(P::Concluded),
+		Err(Error::AlreadyWithdrawn)
+	);
+	assert_eq!(P::Funded.transition(P::Funded), Err(Error::InvalidInput));
+	assert_eq!(P::Disputed.transition(P::Funding), Err(Error::InvalidInput));
+}
+
+#[test]
+/// Tests that a channel passes through its lifecycle phases in order: it is
+/// being funded until the outcome of its announced initial state is deposited,
+/// then funded, disputed, concluded and withdrawn, and concluded again if a
+/// withdrawal fails.
+fn test_channel_phase_sequence() {
+	let mut s = test::Setup::new(false, false);
+	let id = s.params.id();
+	let version = s.state.version;
+	s.state.version = 0;
+	let init = s.sign_state();
+	s.state.version = version;
+
+	let mut phases = vec![s.canister.phase(&id)];
+	assert!(s.canister.channel_status(&id, 0).participants.is_empty());
+	assert_ok!(s.canister.register_funding(s.params.clone(), init.clone()));
+	phases.push(s.canister.phase(&id));
+	for i in 0..s.parts.len() {
+		assert_ok!(s
+			.canister
+			.deposit(s.funding(i), s.state.allocation[0][i].clone()));
+		phases.push(s.canister.phase(&id));
+	}
+	// Announced channels report their funds while being funded.
+	let status = s.canister.channel_status(&id, 0);
+	assert_eq!(status.funded, s.state.total());
+	assert_eq!(
+		status.participants[0].holdings,
+		vec![s.state.allocation[0][0].clone()]
+	);
+	assert_eq!(
+		s.canister.register_funding(s.params.clone(), init),
+		Err(Error::InvalidInput)
+	);
+
+	assert_ok!(s.canister.dispute(s.params.clone(), s.sign_state(), 0));
+	phases.push(s.canister.phase(&id));
+	let timeout = s.params.challenge_duration;
+	assert_eq!(s.canister.settle_expired(timeout).0, vec![id.clone()]);
+	phases.push(s.canister.phase(&id));
+
+	let (mut req, sig) = s.withdrawal(0);
+	assert_ok!(s.canister.begin_withdrawal(req.clone(), sig, timeout));
+	let (other, sig) = s.withdrawal(1);
+	assert_ok!(s.canister.withdraw(other, sig, timeout));
+	// The channel is not withdrawn while a ledger transfer is pending.
+	phases.push(s.canister.phase(&id));
+	assert!(!s.canister.channel_status(&id, timeout).participants[0].withdrawn);
+	assert_eq!(
+		s.canister
+			.finish_withdrawal(&s.funding(0), Err(icp::PayoutError::Rejected)),
+		Err(Error::LedgerError)
+	);
+	phases.push(s.canister.phase(&id));
+	req.nonce += 1;
+	let sig = s.sign_withdrawal(&req, 0);
+	assert_ok!(s.canister.begin_withdrawal(req, sig, timeout));
+	assert_ok!(s.canister.finish_withdrawal(&s.funding(0), Ok(1)));
+	phases.push(s.canister.phase(&id));
+	assert!(s.canister.channel_status(&id, timeout).participants[0].withdrawn);
+
+	use ChannelPhase as P;
+	assert_eq!(
+		phases,
+		[
+			P::Funding,
+			P::Funding,
+			P::Funding,
+			P::Funded,
+			P::Disputed,
+			P::Concluded,
+			P::Concluded,
+			P::Concluded,
+			P::Withdrawn
+		]
+	);
+}
+
+#[test]
+/// Tests that the channel status follows a disputed channel through its
+/// lifecycle until all funds are withdrawn.
+fn test_channel_status() {
+	let mut s = test::Setup::new(false, true);
+	let id = s.params.id();
+	let status = s.canister.channel_status(&id, 0);
+	assert_eq!(status.phase, ChannelPhase::Funding);
+	assert!(status.funded.is_empty() && status.participants.is_empty());
+
+	let sstate = s.sign_state();
+	assert_ok!(s.canister.dispute(s.params.clone(), sstate, 0));
+	let timeout = s.params.challenge_duration;
+	let status = s.canister.channel_status(&id, 0);
+	assert_eq!(status.phase, ChannelPhase::Disputed);
+	assert_eq!(status.timeout, Some(timeout));
+	assert_eq!(status.funded, s.state.total());
+	assert_eq!(status.participants.len(), 2);
+	assert_eq!(
+		status.participants[1].holdings,
+		vec![s.state.allocation[0][1].clone()]
+	);
+	assert!(!status.participants[0].withdrawn);
+
+	// Timed out disputes are concluded before their settlement is processed.
+	assert_eq!(
+		s.canister.channel_status(&id, timeout).phase,
+		ChannelPhase::Concluded
+	);
+	assert_eq!(s.canister.phase(&id), ChannelPhase::Disputed);
+
+	let (req, sig) = s.withdrawal(0);
+	assert_ok!(s.canister.withdraw(req, sig, timeout));
+	let status = s.canister.channel_status(&id, timeout);
+	assert_eq!(status.phase, ChannelPhase::Concluded);
+	assert!(status.participants[0].withdrawn);
+	assert!(!status.participants[1].withdrawn);
+
+	let (req, sig) = s.withdrawal(1);
+	assert_ok!(s.canister.withdraw(req, sig, timeout));
+	assert_eq!(s.canister.phase(&id), ChannelPhase::Withdrawn);
+	assert!(s
+		.canister
+		.channel_status(&id, timeout)
+		.participants
+		.iter()
+		.all(|p| p.withdrawn));
+
+	// The scheduled settlement keeps the channel withdrawn.
+	assert_eq!(s.canister.settle_expired(timeout).0, vec![id.clone()]);
+	assert_eq!(s.canister.phase(&id), ChannelPhase::Withdrawn);
+}
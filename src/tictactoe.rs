@@ -0,0 +1,164 @@
+//  Copyright 2022 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! A two-player tic-tac-toe app. The winner receives all funds of the channel,
+//! a draw leaves the balances unchanged.
+
+use crate::app::App;
+use crate::error::{Error, Result};
+use crate::require;
+use crate::types::*;
+
+/// The identifier of the app in the channel parameters.
+pub const APP_ID: &str = "tictactoe";
+
+/// The length of the encoded [Board]: the next actor followed by the nine
+/// fields, row by row.
+const DATA_LEN: usize = 10;
+
+/// The lines that win the game when completely marked by one player.
+const LINES: [[usize; 3]; 8] = [
+	[0, 1, 2],
+	[3, 4, 5],
+	[6, 7, 8],
+	[0, 3, 6],
+	[1, 4, 7],
+	[2, 5, 8],
+	[0, 4, 8],
+	[2, 4, 6],
+];
+
+pub struct TicTacToe;
+
+/// The game state, encoded in the channel state's data.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Board {
+	/// The index of the participant whose turn it is.
+	pub next_actor: u8,
+	/// The fields, row by row. 0 marks an empty field, 1 and 2 fields marked
+	/// by the first and second participant.
+	pub grid: [u8; 9],
+}
+
+impl Board {
+	/// Creates an empty board on which the given participant starts.
+	pub fn new(first_actor: u8) -> Self {
+		Self {
+			next_actor: first_actor,
+			grid: [0; 9],
+		}
+	}
+
+	pub fn decode(data: &[u8]) -> Result<Self> {
+		require!(data.len() == DATA_LEN, InvalidInput);
+		require!(data[0] < 2, InvalidInput);
+		require!(data[1..].iter().all(|&f| f <= 2), InvalidInput);
+		let mut grid = [0; 9];
+		grid.copy_from_slice(&data[1..]);
+		Ok(Self {
+			next_actor: data[0],
+			grid,
+		})
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let mut data = vec![self.next_actor];
+		data.extend_from_slice(&self.grid);
+		data
+	}
+
+	/// Marks a field for the participant whose turn it is and passes the turn
+	/// to the other participant.
+	pub fn mark(&mut self, field: usize) {
+		self.grid[field] = self.next_actor + 1;
+		self.next_actor = 1 - self.next_actor;
+	}
+
+	/// Returns the index of the participant who completed a line, if any.
+	pub fn winner(&self) -> Option<usize> {
+		LINES
+			.iter()
+			.map(|line| line.map(|f| self.grid[f]))
+			.find(|marks| marks[0] != 0 && marks[0] == marks[1] && marks[1] == marks[2])
+			.map(|marks| marks[0] as usize - 1)
+	}
+
+	/// Whether the game is over, i.e., won or all fields are marked.
+	pub fn over(&self) -> bool {
+		self.winner().is_some() || self.grid.iter().all(|&f| f != 0)
+	}
+}
+
+/// Returns the allocation after a game: the winner receives all funds, a draw
+/// leaves the allocation unchanged.
+pub fn outcome(allocation: &[Vec<Amount>], winner: Option<usize>) -> Vec<Vec<Amount>> {
+	match winner {
+		None => allocation.to_vec(),
+		Some(winner) => allocation
+			.iter()
+			.map(|balances| {
+				let total = balances
+					.iter()
+					.fold(Amount::default(), |x, y| x + y.clone());
+				(0..balances.len())
+					.map(|i| {
+						if i == winner {
+							total.clone()
+						} else {
+							Amount::default()
+						}
+					})
+					.collect()
+			})
+			.collect(),
+	}
+}
+
+impl App for TicTacToe {
+	fn valid_init(&self, params: &Params, state: &State) -> Result<()> {
+		require!(params.participants.len() == 2, InvalidInput);
+		let board = Board::decode(&state.data)?;
+		require!(board.grid == [0; 9], InvalidInput);
+		Ok(())
+	}
+
+	fn valid_transition(
+		&self,
+		params: &Params,
+		from: &State,
+		to: &State,
+		actor: usize,
+	) -> Result<()> {
+		require!(params.participants.len() == 2, InvalidInput);
+		let old = Board::decode(&from.data)?;
+		let new = Board::decode(&to.data)?;
+		require!(actor == old.next_actor as usize, InvalidTransition);
+		require!(!old.over(), InvalidTransition);
+
+		// Exactly one empty field was marked by the actor.
+		let changed: Vec<usize> = (0..9).filter(|&f| old.grid[f] != new.grid[f]).collect();
+		require!(changed.len() == 1, InvalidTransition);
+		let mut expected = old;
+		require!(expected.grid[changed[0]] == 0, InvalidTransition);
+		expected.mark(changed[0]);
+		require!(new == expected, InvalidTransition);
+
+		require!(to.finalized == new.over(), InvalidTransition);
+		require!(
+			to.allocation == outcome(&from.allocation, new.winner()),
+			InvalidTransition
+		);
+		Ok(())
+	}
+}
@@ -25,6 +25,8 @@ pub use ic_cdk::export::candid::{
 	types::{Serializer, Type},
 	CandidType, Deserialize, Int, Nat,
 };
+use ic_ledger_types::Subaccount;
+use k256::ecdsa::{recoverable, SigningKey, VerifyingKey};
 use serde::de::{Deserializer, Error as _};
 use serde_bytes::ByteBuf;
 
@@ -34,13 +36,31 @@ use serde_bytes::ByteBuf;
 /// A hash as used by the signature scheme.
 pub struct Hash(pub digest::Output<Hasher>);
 
-#[derive(PartialEq, Debug, Default, Clone, Eq)]
-/// A layer-2 account identifier.
-pub struct L2Account(pub PublicKey);
+#[derive(PartialEq, Debug, Clone, Eq)]
+/// A layer-2 account identifier: the public key of one of the supported
+/// signature schemes.
+pub enum L2Account {
+	/// An Ed25519 public key.
+	Ed25519(PublicKey),
+	/// A secp256k1 public key, as used by Ethereum-style identities. Signs the
+	/// Keccak-256 hash of messages with recoverable ECDSA.
+	Secp256k1(VerifyingKey),
+}
 
 #[derive(PartialEq, Clone, Eq)]
-// A layer-2 signature for signing Perun protocol messages.
-pub struct L2Signature(pub Signature);
+// A layer-2 signature for signing Perun protocol messages. Ed25519 signatures
+// have 64 bytes, recoverable secp256k1 signatures 65 bytes (r, s and the
+// recovery id, which may also be given in Ethereum's 27/28 form).
+pub struct L2Signature(pub Vec<u8>);
+
+/// Signs Perun protocol messages on behalf of a layer-2 account.
+pub trait L2Signer {
+	/// The account whose signatures are created.
+	fn account(&self) -> L2Account;
+	/// Signs a message, see [State::sig_message] and
+	/// [WithdrawalRequest::sig_message].
+	fn sign(&self, msg: &[u8]) -> L2Signature;
+}
 
 /// A payable layer-1 account identifier. Could be both a user or a canister.
 pub use ic_cdk::export::candid::Principal as L1Account;
@@ -57,6 +77,11 @@ pub type Nonce = Hash;
 /// Channel state version identifier.
 pub type Version = u64;
 
+/// Domain separator of signed channel states.
+pub const STATE_SIG_DOMAIN: &str = "perun-icp/state/v1";
+/// Domain separator of signed withdrawal requests.
+pub const WITHDRAWAL_SIG_DOMAIN: &str = "perun-icp/withdraw/v1";
+
 #[derive(Deserialize, CandidType, Clone)]
 /// The immutable parameters and state of a Perun channel.
 pub struct Params {
@@ -64,28 +89,67 @@ pub struct Params {
 	pub nonce: Nonce,
 	/// The channel's participants' layer-2 identities.
 	pub participants: Vec<L2Account>,
+	/// The assets held in the channel.
+	pub assets: Vec<Asset>,
 	/// When a dispute occurs, how long to wait for responses.
 	pub challenge_duration: Duration,
+	/// The principals that may withdraw the participants' funds without a
+	/// layer-2 signature, via `withdraw_as_caller`. Either empty or one
+	/// optional principal per participant.
+	pub principals: Vec<Option<L1Account>>,
+	/// The identifier of the built-in app whose rules govern the channel's
+	/// state transitions, see [crate::app]. Payment channels have no app.
+	pub app: Option<String>,
 }
 
-#[derive(Deserialize, CandidType, Default, Clone)]
+#[derive(Deserialize, CandidType, Default, Clone, PartialEq)]
 /// The mutable parameters and state of a Perun channel. Contains
 pub struct State {
 	/// The cannel's unique identifier.
 	pub channel: ChannelId,
 	/// The channel's current state revision number.
 	pub version: Version,
-	/// The channel's asset allocation. Contains each participant's current
-	/// balance in the order of the channel parameters' participant list.
-	pub allocation: Vec<Amount>,
+	/// The channel's asset allocation. Contains, for each asset in the order of
+	/// the channel parameters' asset list, each participant's current balance
+	/// in the order of the channel parameters' participant list.
+	pub allocation: Vec<Vec<Amount>>,
 	/// Whether the channel is finalized, i.e., no more updates can be made and
 	/// funds can be withdrawn immediately. A non-finalized channel has to be
 	/// finalized via the canister after the channel's challenge duration
 	/// elapses.
 	pub finalized: bool,
+	/// The app-specific data of the channel, interpreted by the channel's app.
+	/// Empty for payment channels.
+	pub data: Vec<u8>,
+	/// The funds locked in sub-channels, which are distributed according to
+	/// the sub-channels' outcomes when the channel is concluded.
+	pub locked: Vec<SubAlloc>,
 }
 
-#[derive(Deserialize, CandidType, Default)]
+#[derive(Deserialize, CandidType, Default, Clone, PartialEq)]
+/// Funds of a channel that are locked in a sub-channel or virtual channel.
+pub struct SubAlloc {
+	/// The sub-channel's identifier.
+	pub id: ChannelId,
+	/// The locked funds of each asset, in the order of the parent channel
+	/// parameters' asset list.
+	pub balances: Vec<Amount>,
+	/// The index of the parent channel participant that receives each
+	/// sub-channel participant's outcome, like go-perun's `IndexMap`. For a
+	/// virtual channel, the intermediary receives the outcome of the
+	/// participant that is not part of the parent channel.
+	pub index_map: Vec<u16>,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+/// A sub-channel's parameters along with its final state, as needed to
+/// distribute the funds its parent channel locked in it.
+pub struct SubChannel {
+	pub params: Params,
+	pub state: FullySignedState,
+}
+
+#[derive(Deserialize, CandidType, Default, Clone)]
 /// A channel state, signed by all participants.
 pub struct FullySignedState {
 	/// The channel's state.
@@ -117,16 +181,168 @@ pub struct WithdrawalRequest {
 	pub funding: Funding,
 	/// The layer-1 identity to send the funds to.
 	pub receiver: L1Account,
+	/// Has to exceed the nonces of all previously executed withdrawals of the
+	/// funding, so that the request cannot be replayed.
+	pub nonce: u64,
+	/// The time after which the request is rejected, if any.
+	pub valid_until: Option<Timestamp>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+/// The outcome of a withdrawal that was paid out via the ledger.
+pub struct WithdrawalReceipt {
+	/// The amount transferred to the receiver.
+	pub amount: Amount,
+	/// The ledger's transfer fee, which was deducted from the withdrawn funds.
+	pub fee: Amount,
+	/// The ledger block containing the transfer.
+	pub block: crate::icp::BlockHeight,
 }
 
-#[derive(PartialEq, Clone, Default, Deserialize, Eq, Hash, CandidType)]
-/// Identifies the funds belonging to a certain layer 2 identity within a
-/// certain channel.
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+/// The outcome of a withdrawal's ledger transfer.
+pub enum WithdrawalStatus {
+	/// The transfer was issued, but its outcome is unknown. The withdrawal can
+	/// be retried and is then deduplicated by the ledger.
+	Pending,
+	/// The transfer was executed in the given ledger block.
+	Completed(crate::icp::BlockHeight),
+	/// The ledger rejected the transfer and the funds were credited back to
+	/// the holdings.
+	Failed,
+}
+
+#[derive(Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+/// The lifecycle phase of a channel, as tracked by the canister.
+pub enum ChannelPhase {
+	/// No state was registered yet, the participants may still be depositing
+	/// funds. The canister learns a channel's parameters and outcome when its
+	/// initial state is announced via [crate::CanisterState::register_funding]
+	/// or a state is registered.
+	Funding,
+	/// The announced or registered state's outcome is fully funded.
+	Funded,
+	/// A state was registered for dispute, and newer states may be registered
+	/// until the challenge duration passed.
+	Disputed,
+	/// A disputed app channel's state was advanced by a single participant,
+	/// see [crate::CanisterState::progress].
+	ForceExec,
+	/// The channel's outcome is final and its funds can be withdrawn.
+	Concluded,
+	/// All funds of the concluded channel were withdrawn.
+	Withdrawn,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+/// A channel's lifecycle phase along with its funds.
+pub struct ChannelStatus {
+	pub phase: ChannelPhase,
+	/// The registered state's challenge timeout, if a non-final state is
+	/// registered.
+	pub timeout: Option<Timestamp>,
+	/// The funds currently held in the channel, for each asset in the order of
+	/// the channel parameters' asset list. Empty while the parameters are
+	/// unknown, i.e., before a state is registered.
+	pub funded: Vec<Amount>,
+	/// The funds and withdrawals of each participant, in the order of the
+	/// channel parameters' participant list. Empty while the parameters are
+	/// unknown.
+	pub participants: Vec<ParticipantStatus>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+/// A channel participant's funds and withdrawals.
+pub struct ParticipantStatus {
+	pub participant: L2Account,
+	/// The participant's holdings of each asset.
+	pub holdings: Vec<Amount>,
+	/// The latest withdrawal of each asset via its ledger, if any.
+	pub withdrawals: Vec<Option<WithdrawalEntry>>,
+	/// Whether the channel is concluded and the participant withdrew all of
+	/// their funds, with no ledger transfer pending.
+	pub withdrawn: bool,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+/// Journal entry of the latest withdrawal of a funding. Contains everything
+/// needed to repeat the withdrawal's ledger transfer with identical arguments.
+pub struct WithdrawalEntry {
+	/// The principal receiving the withdrawn funds.
+	pub receiver: L1Account,
+	/// The amount transferred to the receiver.
+	pub amount: Amount,
+	/// The ledger's transfer fee, which was deducted from the withdrawn funds.
+	pub fee: Amount,
+	/// The creation time passed to the ledger for deduplication.
+	pub created_at_time: Timestamp,
+	pub status: WithdrawalStatus,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+/// Journal entry of a sweep of a funding's deposit address whose outcome is
+/// not known yet. Contains everything needed to repeat the sweep's ledger
+/// transfer with identical arguments.
+pub struct SweepEntry {
+	/// The amount transferred to the canister's default account.
+	pub amount: Amount,
+	/// The creation time passed to the ledger for deduplication.
+	pub created_at_time: Timestamp,
+}
+
+#[derive(PartialEq, Clone, Default, Deserialize, Eq, Hash, CandidType, Debug)]
+/// Identifies the funds of a certain asset belonging to a certain layer 2
+/// identity within a certain channel.
 pub struct Funding {
 	/// The channel's unique identifier.
 	pub channel: ChannelId,
 	/// The funds' owner's layer-2 identity within the channel.
 	pub participant: L2Account,
+	/// The funds' asset.
+	pub asset: Asset,
+}
+
+#[derive(PartialEq, Clone, Deserialize, Eq, Hash, PartialOrd, Ord, CandidType, Debug)]
+/// A currency that can be held in a channel, identified by its ledger.
+pub struct Asset {
+	/// The ledger canister used for verifying deposits and paying out
+	/// withdrawals.
+	pub ledger: L1Account,
+	/// The token standard implemented by the ledger.
+	pub standard: TokenStandard,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+/// An asset supported by the canister, along with its ledger's transfer fee.
+pub struct AssetConfig {
+	pub asset: Asset,
+	/// The ledger's transfer fee.
+	pub fee: Amount,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+/// The canister's deployment configuration, supplied as init argument. Allows
+/// deploying the same canister to local replicas, testnets and the mainnet.
+pub struct Config {
+	/// The assets that channels may hold.
+	pub assets: Vec<AssetConfig>,
+	/// The canister to forward events to. If not set, events are stored in the
+	/// Perun canister itself.
+	pub event_canister: Option<L1Account>,
+	/// The principals allowed to administer the canister.
+	pub admins: Vec<L1Account>,
+	/// How long to keep the locally stored events of concluded channels. If
+	/// not set, events are kept forever.
+	pub event_retention: Option<Duration>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+/// The token standards supported for deposits and withdrawals.
+pub enum TokenStandard {
+	/// The native ICP ledger.
+	ICP,
+	/// ICRC-1 ledgers, such as ckBTC or SNS tokens.
+	ICRC1,
 }
 
 // Hash
@@ -186,35 +402,115 @@ impl Hash {
 
 // L2Account
 
+#[derive(CandidType, Deserialize)]
+/// The candid representation of an [L2Account].
+enum L2AccountEncoding {
+	Ed25519(ByteBuf),
+	Secp256k1(ByteBuf),
+}
+
 impl<'de> Deserialize<'de> for L2Account {
 	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
 	where
 		D: Deserializer<'de>,
 	{
-		let bytes = ByteBuf::deserialize(deserializer)?;
-		let pk = PublicKey::from_bytes(bytes.as_slice())
-			.ok()
-			.ok_or(D::Error::invalid_length(bytes.len(), &"public key"))?;
-		Ok(L2Account(pk))
+		match L2AccountEncoding::deserialize(deserializer)? {
+			L2AccountEncoding::Ed25519(bytes) => PublicKey::from_bytes(bytes.as_slice())
+				.map(L2Account::Ed25519)
+				.map_err(|_| D::Error::invalid_length(bytes.len(), &"Ed25519 public key")),
+			L2AccountEncoding::Secp256k1(bytes) => VerifyingKey::from_sec1_bytes(bytes.as_slice())
+				.map(L2Account::Secp256k1)
+				.map_err(|_| D::Error::invalid_length(bytes.len(), &"secp256k1 public key")),
+		}
 	}
 }
 
 impl CandidType for L2Account {
 	fn _ty() -> Type {
-		Type::Vec(Box::new(Type::Nat8))
+		L2AccountEncoding::ty()
 	}
 
 	fn idl_serialize<S>(&self, serializer: S) -> core::result::Result<(), S::Error>
 	where
 		S: Serializer,
 	{
-		serializer.serialize_blob(&self.0.to_bytes())
+		let bytes = ByteBuf::from(self.to_bytes());
+		match self {
+			L2Account::Ed25519(_) => L2AccountEncoding::Ed25519(bytes),
+			L2Account::Secp256k1(_) => L2AccountEncoding::Secp256k1(bytes),
+		}
+		.idl_serialize(serializer)
 	}
 }
 
 impl std::hash::Hash for L2Account {
 	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-		self.0.to_bytes().hash(state);
+		std::mem::discriminant(self).hash(state);
+		self.to_bytes().hash(state);
+	}
+}
+
+impl Default for L2Account {
+	fn default() -> Self {
+		L2Account::Ed25519(Default::default())
+	}
+}
+
+impl L2Account {
+	/// Returns the public key's encoding: 32 bytes for Ed25519 and the 33 byte
+	/// compressed SEC1 encoding for secp256k1.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		match self {
+			L2Account::Ed25519(pk) => pk.to_bytes().to_vec(),
+			L2Account::Secp256k1(pk) => k256::EncodedPoint::from(pk).as_bytes().to_vec(),
+		}
+	}
+
+	/// Checks that a message was signed by the account.
+	pub fn verify(&self, msg: &[u8], sig: &L2Signature) -> CanisterResult<()> {
+		match self {
+			L2Account::Ed25519(pk) => {
+				let sig = Signature::try_from(sig.0.as_slice()).or(Err(Error::Authentication))?;
+				pk.verify_strict(msg, &sig).or(Err(Error::Authentication))
+			}
+			L2Account::Secp256k1(pk) => {
+				let mut sig = sig.0.clone();
+				// Ethereum tooling encodes the recovery id as 27 or 28.
+				if let Some(v @ 27..=28) = sig.get_mut(64) {
+					*v -= 27;
+				}
+				let sig = recoverable::Signature::try_from(sig.as_slice())
+					.or(Err(Error::Authentication))?;
+				let signer = sig
+					.recover_verifying_key(msg)
+					.or(Err(Error::Authentication))?;
+				require!(&signer == pk, Authentication);
+				Ok(())
+			}
+		}
+	}
+}
+
+impl L2Signer for ed25519_dalek::Keypair {
+	fn account(&self) -> L2Account {
+		L2Account::Ed25519(self.public)
+	}
+
+	fn sign(&self, msg: &[u8]) -> L2Signature {
+		use ed25519_dalek::Signer;
+		L2Signature(self.try_sign(msg).unwrap().to_bytes().to_vec())
+	}
+}
+
+impl L2Signer for SigningKey {
+	fn account(&self) -> L2Account {
+		L2Account::Secp256k1(self.verifying_key())
+	}
+
+	fn sign(&self, msg: &[u8]) -> L2Signature {
+		use k256::ecdsa::signature::Signer;
+		let sig: recoverable::Signature = self.try_sign(msg).unwrap();
+		L2Signature(sig.as_ref().to_vec())
 	}
 }
 
@@ -226,9 +522,7 @@ impl<'de> Deserialize<'de> for L2Signature {
 		D: Deserializer<'de>,
 	{
 		let bytes = ByteBuf::deserialize(deserializer)?;
-		let sig = Signature::try_from(bytes.as_slice())
-			.map_err(|_| D::Error::invalid_length(bytes.len(), &"signature"))?;
-		Ok(L2Signature(sig))
+		Ok(L2Signature(bytes.into_vec()))
 	}
 }
 
@@ -241,25 +535,53 @@ impl CandidType for L2Signature {
 	where
 		S: Serializer,
 	{
-		serializer.serialize_blob(&self.0.to_bytes())
+		serializer.serialize_blob(&self.0)
 	}
 }
 
 // State
 
 impl State {
-	pub fn validate_sig(&self, sig: &L2Signature, pk: &L2Account) -> CanisterResult<()> {
-		let enc = Encode!(self).expect("encoding state");
-		pk.0.verify_strict(&enc, &sig.0)
-			.ok()
-			.ok_or(Error::Authentication)
+	/// Returns the message that participants sign to authenticate the state
+	/// towards the given Perun canister.
+	pub fn sig_message(&self, canister: &L1Account) -> Vec<u8> {
+		sig_message(STATE_SIG_DOMAIN, canister, self)
 	}
 
-	/// Calculates the total funds in a channel's state.
-	pub fn total(&self) -> Amount {
+	pub fn validate_sig(
+		&self,
+		sig: &L2Signature,
+		pk: &L2Account,
+		canister: &L1Account,
+	) -> CanisterResult<()> {
+		pk.verify(&self.sig_message(canister), sig)
+	}
+
+	/// Calculates the total funds of each asset in a channel's state,
+	/// including the funds locked in sub-channels.
+	pub fn total(&self) -> Vec<Amount> {
 		self.allocation
 			.iter()
-			.fold(Amount::default(), |x, y| x + y.clone())
+			.enumerate()
+			.map(|(a, balances)| {
+				let locked = self.locked.iter().filter_map(|sub| sub.balances.get(a));
+				balances
+					.iter()
+					.chain(locked)
+					.fold(Amount::default(), |x, y| x + y.clone())
+			})
+			.collect()
+	}
+
+	/// Adds funds released from sub-channels, for each asset and participant,
+	/// to the allocation and removes the sub-allocations.
+	pub fn merge_released(&mut self, released: Vec<Vec<Amount>>) {
+		for (balances, released) in self.allocation.iter_mut().zip(released) {
+			for (balance, amount) in balances.iter_mut().zip(released) {
+				*balance += amount;
+			}
+		}
+		self.locked.clear();
 	}
 
 	/// Channels that are in their initial state may not yet be fully funded,
@@ -276,36 +598,81 @@ impl Params {
 	pub fn id(&self) -> ChannelId {
 		Hash::digest(&Encode!(self).unwrap())
 	}
+
+	/// Returns the principal bound to a participant, if any.
+	pub fn principal(&self, participant: &L2Account) -> Option<L1Account> {
+		let i = self.participants.iter().position(|p| p == participant)?;
+		self.principals.get(i).cloned().flatten()
+	}
 }
 
 // FullySignedState
 
 impl FullySignedState {
-	/// Checks that a channel state is authenticated and matches the supplied
-	/// parameters and its outcome does not exceed the supplied total deposits.
-	pub fn validate(&self, params: &Params) -> CanisterResult<()> {
+	/// Checks that a channel state is authenticated towards the given canister
+	/// and matches the supplied parameters.
+	pub fn validate(&self, params: &Params, canister: &L1Account) -> CanisterResult<()> {
 		require!(self.state.channel == params.id(), InvalidInput);
 		require!(self.sigs.len() == params.participants.len(), InvalidInput);
-		require!(self.sigs.len() == self.state.allocation.len(), InvalidInput);
+		require!(
+			params.principals.is_empty() || params.principals.len() == params.participants.len(),
+			InvalidInput
+		);
+		require!(
+			self.state.allocation.len() == params.assets.len(),
+			InvalidInput
+		);
+		for (i, asset) in params.assets.iter().enumerate() {
+			require!(!params.assets[..i].contains(asset), InvalidInput);
+		}
+		for balances in self.state.allocation.iter() {
+			require!(self.sigs.len() == balances.len(), InvalidInput);
+		}
+		for (i, sub) in self.state.locked.iter().enumerate() {
+			require!(sub.balances.len() == params.assets.len(), InvalidInput);
+			require!(
+				sub.index_map
+					.iter()
+					.all(|&k| (k as usize) < params.participants.len()),
+				InvalidInput
+			);
+			require!(sub.id != self.state.channel, InvalidInput);
+			require!(
+				self.state.locked[..i].iter().all(|s| s.id != sub.id),
+				InvalidInput
+			);
+		}
 
 		for (i, pk) in params.participants.iter().enumerate() {
-			self.state.validate_sig(&self.sigs[i], pk)?;
+			self.state.validate_sig(&self.sigs[i], pk, canister)?;
 		}
 
 		Ok(())
 	}
 
-	pub fn validate_final(&self, params: &Params) -> CanisterResult<()> {
+	pub fn validate_final(&self, params: &Params, canister: &L1Account) -> CanisterResult<()> {
 		require!(self.state.finalized, NotFinalized);
-		self.validate(params)
+		self.validate(params, canister)
 	}
 }
 
+/// Encodes a signed message: the domain separator of the message type, the
+/// principal of the canister the message is meant for, and the message itself.
+/// This prevents signatures from being valid for other message types or
+/// canister deployments.
+fn sig_message<T: CandidType>(domain: &str, canister: &L1Account, msg: &T) -> Vec<u8> {
+	Encode!(&domain, canister, msg).expect("encoding signed message")
+}
+
 // RegisteredState
 
 impl RegisteredState {
-	pub fn conclude(state: FullySignedState, params: &Params) -> CanisterResult<Self> {
-		state.validate_final(params)?;
+	pub fn conclude(
+		state: FullySignedState,
+		params: &Params,
+		canister: &L1Account,
+	) -> CanisterResult<Self> {
+		state.validate_final(params, canister)?;
 		Ok(Self {
 			state: state.state,
 			timeout: Default::default(),
@@ -316,8 +683,9 @@ impl RegisteredState {
 		state: FullySignedState,
 		params: &Params,
 		now: Timestamp,
+		canister: &L1Account,
 	) -> CanisterResult<Self> {
-		state.validate(params)?;
+		state.validate(params, canister)?;
 		Ok(Self {
 			state: state.state,
 			timeout: now + params.challenge_duration,
@@ -332,31 +700,147 @@ impl RegisteredState {
 // WithdrawalRequest
 
 impl WithdrawalRequest {
-	pub fn new(funding: Funding, receiver: L1Account) -> Self {
-		Self { funding, receiver }
+	/// Creates a withdrawal request that does not expire.
+	pub fn new(funding: Funding, receiver: L1Account, nonce: u64) -> Self {
+		Self {
+			funding,
+			receiver,
+			nonce,
+			valid_until: None,
+		}
 	}
 
-	pub fn validate_sig(&self, sig: &L2Signature) -> CanisterResult<()> {
-		let enc = Encode!(self).expect("encoding withdrawal request");
+	/// Returns the message that the funds' owner signs to authorize the
+	/// withdrawal at the given Perun canister.
+	pub fn sig_message(&self, canister: &L1Account) -> Vec<u8> {
+		sig_message(WITHDRAWAL_SIG_DOMAIN, canister, self)
+	}
+
+	pub fn validate_sig(&self, sig: &L2Signature, canister: &L1Account) -> CanisterResult<()> {
 		self.funding
 			.participant
-			.0
-			.verify_strict(&enc, &sig.0)
-			.ok()
-			.ok_or(Error::Authentication)
+			.verify(&self.sig_message(canister), sig)
+	}
+}
+
+// ChannelPhase
+
+impl ChannelPhase {
+	/// Returns the phase to move into, if moving from this phase to `to` is
+	/// legal. The legal moves are Funding → Funded, any phase before the
+	/// conclusion → Disputed, Disputed or ForceExec → ForceExec, any funded
+	/// phase before the conclusion → Concluded, and Concluded → Withdrawn.
+	pub fn transition(self, to: ChannelPhase) -> CanisterResult<Self> {
+		use ChannelPhase as P;
+		match (self, to) {
+			(P::Funding, P::Funded)
+			| (P::Funding | P::Funded | P::Disputed | P::ForceExec, P::Disputed)
+			| (P::Disputed | P::ForceExec, P::ForceExec)
+			| (P::Funded | P::Disputed | P::ForceExec, P::Concluded)
+			| (P::Concluded, P::Withdrawn) => Ok(to),
+			(P::Withdrawn, _) => Err(Error::AlreadyWithdrawn),
+			(P::Concluded, _) => Err(Error::AlreadyConcluded),
+			(_, P::ForceExec) => Err(Error::NotDisputed),
+			(_, P::Withdrawn) => Err(Error::NotFinalized),
+			(P::Funding, P::Concluded) => Err(Error::InsufficientFunding),
+			_ => Err(Error::InvalidInput),
+		}
+	}
+}
+
+// WithdrawalEntry
+
+impl WithdrawalEntry {
+	/// Returns the withdrawal's receipt if its transfer was executed.
+	pub fn receipt(&self) -> Option<WithdrawalReceipt> {
+		match self.status {
+			WithdrawalStatus::Completed(block) => Some(WithdrawalReceipt {
+				amount: self.amount.clone(),
+				fee: self.fee.clone(),
+				block,
+			}),
+			_ => None,
+		}
+	}
+}
+
+// Asset
+
+impl Default for Asset {
+	/// The ICP token, using the mainnet ICP ledger.
+	fn default() -> Self {
+		Self {
+			ledger: L1Account::from_text(crate::icp::MAINNET_ICP_LEDGER).unwrap(),
+			standard: TokenStandard::ICP,
+		}
+	}
+}
+
+// Config
+
+impl Default for Config {
+	/// The configuration for the ICP mainnet ledger without event canister and
+	/// administrators, keeping all events.
+	fn default() -> Self {
+		Self {
+			assets: vec![AssetConfig {
+				asset: Asset::default(),
+				fee: crate::icp::DEFAULT_FEE_E8S.into(),
+			}],
+			event_canister: None,
+			admins: vec![],
+			event_retention: None,
+		}
+	}
+}
+
+impl Config {
+	/// Returns the transfer fee of an asset, or nothing if the asset is not
+	/// supported by the canister.
+	pub fn fee(&self, asset: &Asset) -> Option<Amount> {
+		self.assets
+			.iter()
+			.find(|c| &c.asset == asset)
+			.map(|c| c.fee.clone())
+	}
+
+	/// Returns whether an asset is supported by the canister.
+	pub fn supports(&self, asset: &Asset) -> bool {
+		self.fee(asset).is_some()
+	}
+
+	/// Returns whether a principal is one of the canister's administrators.
+	pub fn is_admin(&self, principal: &L1Account) -> bool {
+		self.admins.contains(principal)
 	}
 }
 
 // Funding
 
 impl Funding {
-	pub fn new(channel: ChannelId, participant: L2Account) -> Self {
+	pub fn new(channel: ChannelId, participant: L2Account, asset: Asset) -> Self {
 		Self {
 			channel,
 			participant,
+			asset,
 		}
 	}
 
+	/// Returns the canister's ledger subaccount to which the funding's deposits
+	/// have to be transferred. It depends on the channel, participant and
+	/// asset, so that assets sharing a ledger, e.g., the ICP ledger via both
+	/// of its token standards, do not share a deposit address, whose funds
+	/// would otherwise be credited for both assets.
+	pub fn subaccount(&self) -> Subaccount {
+		let h = Hash::digest(&Encode!(&self.channel, &self.participant, &self.asset).unwrap());
+		let mut sub = [0u8; 32];
+		sub.copy_from_slice(&h.0[..32]);
+		Subaccount(sub)
+	}
+
+	/// Returns the legacy memo identifying the funding in transfers to the
+	/// canister's default account. Only 64 bit wide, prefer
+	/// [Funding::subaccount] instead.
 	pub fn memo(&self) -> u64 {
 		let h = Hash::digest(&Encode!(self).unwrap());
 		let arr: [u8; 8] = [
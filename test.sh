@@ -32,7 +32,7 @@ export ICP_PERUN_DEFAULT_ACC=`dfx ledger account-id`
 
 dfx deploy ledger --argument '(record {minting_account = "'$ICP_PERUN_MINT_ACC'"; initial_values = vec { record { "'$ICP_PERUN_DEFAULT_ACC'"; record { e8s=0 } }; }; send_whitelist = vec {}})'
 export ICP_LEDGER_PRINCIPAL=`dfx canister id ledger`
-dfx deploy icp_perun
+dfx deploy icp_perun --argument '(record { assets = vec { record { asset = record { ledger = principal "'$ICP_LEDGER_PRINCIPAL'"; standard = variant { ICP } }; fee = 10_000 } }; event_canister = null; admins = vec {}; event_retention = null })'
 export ICP_PERUN_PRINCIPAL=`dfx canister id icp_perun`
 
 sed -i "s/cdylib/lib/g" Cargo.toml